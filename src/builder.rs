@@ -3,11 +3,98 @@ use crate::*;
 #[derive(Default, Debug, Clone)]
 pub struct Builder<'a> {
     pub instrs: Vec<Instr<'a>>,
+    pub label_names: std::collections::BTreeMap<usize, String>,
+    /// The Rust source location that emitted each run of instructions, keyed by the
+    /// position of the first instruction
+    pub locations: crate::source_map::Locations,
+    pub(crate) features: Option<WasmFeatures>,
+    pub(crate) stack: Option<Stack>,
+    /// Labels of the blocks that are currently open, innermost last
+    pub(crate) blocks: Blocks,
+    /// The module's types, used to intern block signatures, this is set for builders created by
+    /// `Module::func`
+    pub(crate) context: Option<SharedContext>,
+    /// The number of arguments passed to calls made using the call helpers, keyed by the
+    /// position of the call instruction. These are checked by `Module::try_finish`.
+    pub(crate) calls: Vec<(usize, usize)>,
+}
+
+type CaseBody<'a, 'c> = Box<dyn FnOnce(&mut Builder<'a>, Label) + 'c>;
+
+/// A case of `Builder::switch`, the body is given the label of the end of the switch
+pub struct Case<'a, 'c> {
+    body: CaseBody<'a, 'c>,
+    fall_through: bool,
+}
+
+impl<'a, 'c> Case<'a, 'c> {
+    /// A case that breaks out of the switch when its body is finished
+    pub fn new(body: impl FnOnce(&mut Builder<'a>, Label) + 'c) -> Self {
+        Case {
+            body: Box::new(body),
+            fall_through: false,
+        }
+    }
+
+    /// A case that continues with the next case (or the default) when its body is finished
+    pub fn fall_through(body: impl FnOnce(&mut Builder<'a>, Label) + 'c) -> Self {
+        Case {
+            body: Box::new(body),
+            fall_through: true,
+        }
+    }
+}
+
+/// The type of a block, either a `BlockType` or a `(params, results)` signature. Signatures that
+/// need a function type are interned when the block is pushed, which is only possible in a
+/// builder created by `Module::func`.
+#[derive(Debug, Clone)]
+pub enum BlockSignature {
+    Type(BlockType),
+    Signature(Vec<ValType>, Vec<ValType>),
+}
+
+impl From<BlockType> for BlockSignature {
+    fn from(value: BlockType) -> Self {
+        BlockSignature::Type(value)
+    }
+}
+
+impl<P: IntoIterator<Item = ValType>, R: IntoIterator<Item = ValType>> From<(P, R)>
+    for BlockSignature
+{
+    fn from((params, results): (P, R)) -> Self {
+        BlockSignature::Signature(params.into_iter().collect(), results.into_iter().collect())
+    }
+}
+
+handle!(LabelHandle);
+/// A block label, labels are unique across builders so a label from another builder is
+/// never in scope, this includes clones of the builder
+pub type Label = Index<LabelHandle>;
+
+static NEXT_LABEL: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+fn next_label() -> u32 {
+    NEXT_LABEL.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// The labels of open blocks, a clone gets new labels for the same blocks
+#[derive(Debug, Default)]
+pub(crate) struct Blocks(Vec<u32>);
+
+impl Clone for Blocks {
+    fn clone(&self) -> Self {
+        Blocks(self.0.iter().map(|_| next_label()).collect())
+    }
 }
 
 impl<'a> From<Vec<Instr<'a>>> for Builder<'a> {
     fn from(instrs: Vec<Instr<'a>>) -> Self {
-        Builder { instrs }
+        Builder {
+            instrs,
+            ..Default::default()
+        }
     }
 }
 
@@ -15,58 +102,148 @@ impl<'a> Builder<'a> {
     pub fn new(init: impl IntoIterator<Item = Instr<'a>>) -> Self {
         Builder {
             instrs: init.into_iter().collect(),
+            ..Default::default()
         }
     }
 
+    /// Name the label of the next `block`, `loop`, `if` or `try_table`
+    pub fn label(&mut self, name: impl Into<String>) -> &mut Self {
+        self.label_names.insert(self.instrs.len(), name.into());
+        self
+    }
+
+    #[track_caller]
     pub fn push(&mut self, x: impl Expr<'a>) -> &mut Self {
         x.expr(self);
         self
     }
 
+    #[track_caller]
     pub fn extend(&mut self, x: impl IntoIterator<Item = Instr<'a>>) -> &mut Self {
+        let pos = self.instrs.len();
         self.instrs.extend(x);
+        if let Some(features) = self.features {
+            for instr in &self.instrs[pos..] {
+                features::check_instr(features, instr);
+            }
+        }
+        for instr in &self.instrs[pos..] {
+            match instr {
+                Instr::Block(_)
+                | Instr::Loop(_)
+                | Instr::If(_)
+                | Instr::TryTable(..)
+                | Instr::Try(_) => {
+                    self.blocks.0.push(next_label());
+                }
+                Instr::End | Instr::Delegate(_) => {
+                    self.blocks.0.pop();
+                }
+                _ => (),
+            }
+        }
+        if let Some(stack) = &mut self.stack {
+            for (i, instr) in self.instrs.iter().enumerate().skip(pos) {
+                if let Err(e) = stack.apply(instr) {
+                    panic!("Type error at instruction {i} `{instr:?}`: {e}");
+                }
+            }
+        }
+        if self.instrs.len() > pos {
+            self.set_location(pos, std::panic::Location::caller());
+        }
         self
     }
 
+    pub(crate) fn set_location(&mut self, pos: usize, loc: &'static std::panic::Location<'static>) {
+        match self.locations.last_mut() {
+            Some((_, last)) if *last == loc => (),
+            Some((p, last)) if *p == pos => *last = loc,
+            _ => self.locations.push((pos, loc)),
+        }
+    }
+
+    pub(crate) fn set_context(&mut self, context: &SharedContext) {
+        self.context = Some(context.clone());
+        if let Some(stack) = &mut self.stack {
+            stack.set_context(context);
+        }
+    }
+
+    /// The operand stack, only available when the builder was created by a typed module, see
+    /// `Module::typed`
+    pub fn stack(&self) -> Option<&Stack> {
+        self.stack.as_ref()
+    }
+
+    pub fn location(&self, index: usize) -> Option<&'static std::panic::Location<'static>> {
+        let n = self.locations.partition_point(|(pos, _)| *pos <= index);
+        n.checked_sub(1).map(|n| self.locations[n].1)
+    }
+
+    #[track_caller]
     pub fn local_incr(&mut self, local: Local, ty: ValType) -> &mut Self {
-        self.push(Instr::LocalGet(local.0));
-        match ty {
-            ValType::I64 => {
-                self.push([Instr::I64Const(1), Instr::I64Add]);
-            }
-            ValType::I32 => {
-                self.push([Instr::I32Const(1), Instr::I32Add]);
-            }
-            ValType::F64 => {
-                self.push([Instr::F64Const(1.0), Instr::F64Add]);
-            }
-            ValType::F32 => {
-                self.push([Instr::F32Const(1.0), Instr::F32Add]);
+        self.try_local_incr(local, ty)
+            .unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// Increment a numeric local by one using the local type from the operand stack, this
+    /// requires a typed builder
+    #[track_caller]
+    pub fn incr(&mut self, local: Local) -> Result<&mut Self, Error> {
+        let ty = self
+            .stack
+            .as_ref()
+            .and_then(|stack| stack.local(local.0))
+            .ok_or(Error::UnknownType { helper: "incr" })?;
+        self.try_local_incr(local, ty)
+    }
+
+    /// Increment a numeric local by one, returning an error for non-numeric types
+    #[track_caller]
+    pub fn try_local_incr(&mut self, local: Local, ty: ValType) -> Result<&mut Self, Error> {
+        let (one, add): (Instr, Instr) = match ty {
+            ValType::I64 => (Instr::I64Const(1), Instr::I64Add),
+            ValType::I32 => (Instr::I32Const(1), Instr::I32Add),
+            ValType::F64 => (Instr::F64Const(1.0), Instr::F64Add),
+            ValType::F32 => (Instr::F32Const(1.0), Instr::F32Add),
+            ty => {
+                return Err(Error::InvalidType {
+                    helper: "local_incr",
+                    ty,
+                })
             }
-            x => panic!("Invalid type in `local_incr`: {x:?}"),
-        }
+        };
+        self.push(Instr::LocalGet(local.0));
+        self.push([one, add]);
         self.push(Instr::LocalSet(local.0));
-        self
+        Ok(self)
     }
 
-    pub fn block<F: Expr<'a>>(&mut self, bt: BlockType, expr: F) -> &mut Self {
+    #[track_caller]
+    pub fn block<F: Expr<'a>>(&mut self, bt: impl Into<BlockSignature>, expr: F) -> &mut Self {
+        let bt = self.block_type(bt);
         self.push(Instr::Block(bt));
         self.push(expr);
         self.push(Instr::End)
     }
 
-    pub fn loop_<F: Expr<'a>>(&mut self, bt: BlockType, expr: F) -> &mut Self {
+    #[track_caller]
+    pub fn loop_<F: Expr<'a>>(&mut self, bt: impl Into<BlockSignature>, expr: F) -> &mut Self {
+        let bt = self.block_type(bt);
         self.push(Instr::Loop(bt));
         self.push(expr);
         self.push(Instr::End)
     }
 
+    #[track_caller]
     pub fn loop_while<C: Expr<'a>, F: Expr<'a>>(
         &mut self,
-        bt: BlockType,
+        bt: impl Into<BlockSignature>,
         cond: C,
         expr: F,
     ) -> &mut Self {
+        let bt = self.block_type(bt);
         self.push(Instr::Loop(bt));
         self.push(cond);
         self.push(expr);
@@ -74,26 +251,30 @@ impl<'a> Builder<'a> {
         self.push(Instr::End)
     }
 
+    #[track_caller]
     pub fn if_then<F: Expr<'a>>(
         &mut self,
-        bt: BlockType,
+        bt: impl Into<BlockSignature>,
         cond: impl Expr<'a>,
         expr: F,
     ) -> &mut Self {
         self.push(cond);
+        let bt = self.block_type(bt);
         self.push(Instr::If(bt));
         self.push(expr);
         self.push(Instr::End)
     }
 
+    #[track_caller]
     pub fn if_then_else<F: Expr<'a>, G: Expr<'a>>(
         &mut self,
-        bt: BlockType,
+        bt: impl Into<BlockSignature>,
         cond: impl Expr<'a>,
         expr: F,
         else_: G,
     ) -> &mut Self {
         self.push(cond);
+        let bt = self.block_type(bt);
         self.push(Instr::If(bt));
         self.push(expr);
         self.push(Instr::Else);
@@ -101,6 +282,302 @@ impl<'a> Builder<'a> {
         self.push(Instr::End)
     }
 
+    /// The block type for a block instruction, interning a function type when it's needed
+    #[track_caller]
+    fn block_type(&mut self, bt: impl Into<BlockSignature>) -> BlockType {
+        match bt.into() {
+            BlockSignature::Type(bt) => bt,
+            BlockSignature::Signature(params, results) => match (&params[..], &results[..]) {
+                ([], []) => BlockType::Empty,
+                ([], [ty]) => BlockType::Result(*ty),
+                _ => {
+                    let context = self.context.as_ref().expect(
+                        "Block signatures with parameters or multiple results need a builder \
+                         created by `Module::func`, use `Module::block_type` instead",
+                    );
+                    if let Some(features) = self.features {
+                        features::check_func_type(features, &params, &results);
+                    }
+                    let ty = wasm_encoder::FuncType::new(params, results);
+                    BlockType::FunctionType(context.lock().intern(InternedType::Func(ty)))
+                }
+            },
+        }
+    }
+
+    /// The label of the innermost open block
+    fn current_label(&self) -> Label {
+        Label::from(*self.blocks.0.last().expect("no open block"))
+    }
+
+    /// The relative depth of `label` from the current position, this fails when the block the
+    /// label refers to isn't open
+    pub fn depth(&self, label: Label) -> Result<u32, Error> {
+        self.blocks
+            .0
+            .iter()
+            .rev()
+            .position(|l| *l == label.0)
+            .map(|depth| depth as u32)
+            .ok_or(Error::LabelOutOfScope(label))
+    }
+
+    #[track_caller]
+    pub(crate) fn depth_or_panic(&self, label: Label) -> u32 {
+        self.depth(label).unwrap_or_else(|e| panic!("{e}"))
+    }
+
+    /// A `catch_all` clause for the next `try_table` that branches to `label`
+    #[track_caller]
+    pub fn catch_all(&self, label: Label) -> Catch {
+        Catch::All {
+            label: self.depth_or_panic(label),
+        }
+    }
+
+    /// A `catch_all_ref` clause for the next `try_table` that branches to `label`
+    #[track_caller]
+    pub fn catch_all_ref(&self, label: Label) -> Catch {
+        Catch::AllRef {
+            label: self.depth_or_panic(label),
+        }
+    }
+
+    /// Like `block`, but the body is given the block's label
+    #[track_caller]
+    pub fn labeled_block(
+        &mut self,
+        bt: impl Into<BlockSignature>,
+        f: impl FnOnce(&mut Builder<'a>, Label),
+    ) -> &mut Self {
+        let bt = self.block_type(bt);
+        self.push(Instr::Block(bt));
+        f(self, self.current_label());
+        self.push(Instr::End)
+    }
+
+    /// Like `loop_`, but the body is given the loop's label, branching to it continues the loop
+    #[track_caller]
+    pub fn labeled_loop(
+        &mut self,
+        bt: impl Into<BlockSignature>,
+        f: impl FnOnce(&mut Builder<'a>, Label),
+    ) -> &mut Self {
+        let bt = self.block_type(bt);
+        self.push(Instr::Loop(bt));
+        f(self, self.current_label());
+        self.push(Instr::End)
+    }
+
+    /// Like `if_then`, but the body is given the label of the `if`
+    #[track_caller]
+    pub fn labeled_if(
+        &mut self,
+        bt: impl Into<BlockSignature>,
+        cond: impl Expr<'a>,
+        f: impl FnOnce(&mut Builder<'a>, Label),
+    ) -> &mut Self {
+        self.push(cond);
+        let bt = self.block_type(bt);
+        self.push(Instr::If(bt));
+        f(self, self.current_label());
+        self.push(Instr::End)
+    }
+
+    /// Like `if_then_else`, but both branches are given the label of the `if`
+    #[track_caller]
+    pub fn labeled_if_else(
+        &mut self,
+        bt: impl Into<BlockSignature>,
+        cond: impl Expr<'a>,
+        f: impl FnOnce(&mut Builder<'a>, Label),
+        g: impl FnOnce(&mut Builder<'a>, Label),
+    ) -> &mut Self {
+        self.push(cond);
+        let bt = self.block_type(bt);
+        self.push(Instr::If(bt));
+        let label = self.current_label();
+        f(self, label);
+        self.push(Instr::Else);
+        g(self, label);
+        self.push(Instr::End)
+    }
+
+    /// Dispatch on `selector` using `br_table`, case `n` runs when the selector is `n` and
+    /// `default` runs for any other value. Cases are laid out in order so a fall-through case
+    /// continues with the next one.
+    #[track_caller]
+    pub fn switch<'c>(
+        &mut self,
+        selector: impl Expr<'a>,
+        cases: impl IntoIterator<Item = Case<'a, 'c>>,
+        default: impl FnOnce(&mut Builder<'a>, Label),
+    ) -> &mut Self {
+        let cases = cases.into_iter().collect::<Vec<_>>();
+        self.push(Instr::Block(BlockType::Empty));
+        let exit = self.current_label();
+        self.push(Instr::Block(BlockType::Empty));
+        let default_label = self.current_label();
+        let mut labels = vec![];
+        for _ in &cases {
+            self.push(Instr::Block(BlockType::Empty));
+            labels.push(self.current_label());
+        }
+        labels.reverse();
+
+        self.push(selector);
+        self.br_table(labels, default_label);
+        for case in cases {
+            self.push(Instr::End);
+            (case.body)(self, exit);
+            if !case.fall_through {
+                self.br(exit);
+            }
+        }
+        self.push(Instr::End);
+        default(self, exit);
+        self.push(Instr::End)
+    }
+
+    #[track_caller]
+    pub fn br(&mut self, label: Label) -> &mut Self {
+        self.push(Instr::Br(self.depth_or_panic(label)))
+    }
+
+    #[track_caller]
+    pub fn br_if(&mut self, label: Label) -> &mut Self {
+        self.push(Instr::BrIf(self.depth_or_panic(label)))
+    }
+
+    #[track_caller]
+    pub fn br_table(
+        &mut self,
+        labels: impl IntoIterator<Item = Label>,
+        default: Label,
+    ) -> &mut Self {
+        let labels = labels
+            .into_iter()
+            .map(|label| self.depth_or_panic(label))
+            .collect::<Vec<_>>();
+        let default = self.depth_or_panic(default);
+        self.push(Instr::BrTable(labels.into(), default))
+    }
+
+    #[track_caller]
+    pub fn br_on_null(&mut self, label: Label) -> &mut Self {
+        self.push(Instr::BrOnNull(self.depth_or_panic(label)))
+    }
+
+    #[track_caller]
+    pub fn br_on_non_null(&mut self, label: Label) -> &mut Self {
+        self.push(Instr::BrOnNonNull(self.depth_or_panic(label)))
+    }
+
+    #[track_caller]
+    pub fn br_on_cast(&mut self, label: Label, from: RefType, to: RefType) -> &mut Self {
+        self.push(Instr::BrOnCast {
+            relative_depth: self.depth_or_panic(label),
+            from_ref_type: from,
+            to_ref_type: to,
+        })
+    }
+
+    #[track_caller]
+    pub fn br_on_cast_fail(&mut self, label: Label, from: RefType, to: RefType) -> &mut Self {
+        self.push(Instr::BrOnCastFail {
+            relative_depth: self.depth_or_panic(label),
+            from_ref_type: from,
+            to_ref_type: to,
+        })
+    }
+
+    #[track_caller]
+    pub fn try_table<F: Expr<'a>>(
+        &mut self,
+        bt: impl Into<BlockSignature>,
+        catches: impl IntoIterator<Item = Catch>,
+        expr: F,
+    ) -> &mut Self {
+        let catches = catches.into_iter().collect::<Vec<_>>();
+        let bt = self.block_type(bt);
+        self.push(Instr::TryTable(bt, catches.into()));
+        self.push(expr);
+        self.push(Instr::End)
+    }
+
+    #[track_caller]
+    fn call_with<A: Args<'a>>(&mut self, args: A, instr: Instr<'a>) -> &mut Self {
+        args.push_args(self);
+        self.calls.push((self.instrs.len(), A::COUNT));
+        self.push(instr)
+    }
+
+    /// Push `args` followed by a call to `f`
+    #[track_caller]
+    pub fn call(&mut self, f: FunctionIndex, args: impl Args<'a>) -> &mut Self {
+        self.call_with(args, Instr::Call(f.index()))
+    }
+
+    #[track_caller]
+    pub fn return_call(&mut self, f: FunctionIndex, args: impl Args<'a>) -> &mut Self {
+        self.call_with(args, Instr::ReturnCall(f.index()))
+    }
+
+    /// Push `args` followed by `call_indirect`, the last argument is the index into `table`
+    #[track_caller]
+    pub fn call_indirect(
+        &mut self,
+        table: TableIndex,
+        ty: FunctionTypeIndex,
+        args: impl Args<'a>,
+    ) -> &mut Self {
+        self.call_with(
+            args,
+            Instr::CallIndirect {
+                type_index: ty.index(),
+                table_index: table.index(),
+            },
+        )
+    }
+
+    #[track_caller]
+    pub fn return_call_indirect(
+        &mut self,
+        table: TableIndex,
+        ty: FunctionTypeIndex,
+        args: impl Args<'a>,
+    ) -> &mut Self {
+        self.call_with(
+            args,
+            Instr::ReturnCallIndirect {
+                type_index: ty.index(),
+                table_index: table.index(),
+            },
+        )
+    }
+
+    /// Push `args` followed by `call_ref`, the last argument is the function reference
+    #[track_caller]
+    pub fn call_ref(&mut self, ty: FunctionTypeIndex, args: impl Args<'a>) -> &mut Self {
+        self.call_with(args, Instr::CallRef(ty.index()))
+    }
+
+    #[track_caller]
+    pub fn return_call_ref(&mut self, ty: FunctionTypeIndex, args: impl Args<'a>) -> &mut Self {
+        self.call_with(args, Instr::ReturnCallRef(ty.index()))
+    }
+
+    #[track_caller]
+    pub fn throw(&mut self, tag: TagIndex) -> &mut Self {
+        self.push(Instr::Throw(tag.index()))
+    }
+
+    #[track_caller]
+    pub fn throw_ref(&mut self) -> &mut Self {
+        self.push(Instr::ThrowRef)
+    }
+
+    #[track_caller]
     pub fn return_(&mut self) {
         self.push(Instr::Return);
     }
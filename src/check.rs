@@ -0,0 +1,242 @@
+use crate::*;
+
+use context::Context;
+use wasmparser::WasmModuleResources;
+
+/// The types of the entities a function body can refer to, in handle order
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Entities {
+    pub tables: Vec<TableType>,
+    pub memories: Vec<MemoryType>,
+    pub globals: Vec<GlobalType>,
+    /// The function type index of each tag
+    pub tags: Vec<u32>,
+    pub elements: Vec<RefType>,
+}
+
+impl Entities {
+    fn counts(&self, types: usize) -> [usize; 6] {
+        [
+            types,
+            self.tables.len(),
+            self.memories.len(),
+            self.globals.len(),
+            self.tags.len(),
+            self.elements.len(),
+        ]
+    }
+}
+
+/// Validator resources for checking functions one at a time. They come from a header module
+/// with the module's types that imports one function of each function type and every table,
+/// memory, global and tag, so its index spaces match the handles used in function bodies.
+/// Functions aren't part of the header, their types are looked up in the module's context.
+pub(crate) struct Checker {
+    key: (WasmFeatures, [usize; 6]),
+    resources: wasmparser::ValidatorResources,
+    /// The imported function with each function type, by type index
+    funcs: Vec<Option<u32>>,
+    elements: Vec<Option<wasmparser::RefType>>,
+}
+
+impl Checker {
+    /// Whether the checker still matches the module, it has to be rebuilt when types or
+    /// entities other than functions are added
+    pub fn is_current(
+        &self,
+        features: WasmFeatures,
+        context: &Context,
+        entities: &Entities,
+    ) -> bool {
+        self.key == (features, entities.counts(context.types.len()))
+    }
+
+    pub fn new(
+        types: &wasm_encoder::TypeSection,
+        context: &Context,
+        entities: &Entities,
+        features: WasmFeatures,
+    ) -> Result<Checker, wasmparser::BinaryReaderError> {
+        let mut imports = wasm_encoder::ImportSection::new();
+        let mut count = 0;
+        let funcs = context
+            .types
+            .iter()
+            .enumerate()
+            .map(|(index, ty)| {
+                ty.as_ref()?;
+                imports.import("", "", wasm_encoder::EntityType::Function(index as u32));
+                count += 1;
+                Some(count - 1)
+            })
+            .collect::<Vec<_>>();
+        for ty in &entities.tables {
+            imports.import("", "", *ty);
+        }
+        for ty in &entities.memories {
+            imports.import("", "", *ty);
+        }
+        for ty in &entities.globals {
+            imports.import("", "", *ty);
+        }
+        for ty in &entities.tags {
+            let tag = wasm_encoder::TagType {
+                kind: wasm_encoder::TagKind::Exception,
+                func_type_idx: *ty,
+            };
+            imports.import("", "", tag);
+        }
+
+        // The validator only hands out its resources for a function body, so the header has
+        // one function that's never validated
+        let ty = funcs
+            .iter()
+            .position(Option::is_some)
+            .expect("a function type is needed to check a function");
+        let mut functions = wasm_encoder::FunctionSection::new();
+        functions.function(ty as u32);
+        let mut body = wasm_encoder::Function::new([]);
+        body.instruction(&Instr::Unreachable)
+            .instruction(&Instr::End);
+        let mut code = wasm_encoder::CodeSection::new();
+        code.function(&body);
+
+        let mut module = wasm_encoder::Module::new();
+        module
+            .section(types)
+            .section(&imports)
+            .section(&functions)
+            .section(&code);
+        let bytes = module.finish();
+
+        let mut validator = wasmparser::Validator::new_with_features(features);
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            if let wasmparser::ValidPayload::Func(func, _) = validator.payload(&payload?)? {
+                let resources = func.resources;
+                let elements = entities
+                    .elements
+                    .iter()
+                    .map(|ty| {
+                        let mut bytes = vec![];
+                        wasm_encoder::Encode::encode(ty, &mut bytes);
+                        let mut ty = wasmparser::BinaryReader::new(&bytes, 0).read().ok()?;
+                        resources.check_ref_type(&mut ty, 0).ok()?;
+                        Some(ty)
+                    })
+                    .collect();
+                return Ok(Checker {
+                    key: (features, entities.counts(context.types.len())),
+                    resources,
+                    funcs,
+                    elements,
+                });
+            }
+        }
+        unreachable!("the header module has a function")
+    }
+
+    /// Validate the body of `def`, error offsets are relative to the start of the body
+    pub fn validate(
+        &self,
+        def: &Function,
+        context: &Context,
+        data: u32,
+        features: WasmFeatures,
+    ) -> Result<(), Box<ValidationError>> {
+        let bytes = def.encode_body().into_raw_body();
+        let body = wasmparser::FunctionBody::new(wasmparser::BinaryReader::new(&bytes, 0));
+        let resources = Resources {
+            checker: self,
+            context,
+            data,
+        };
+        let func = wasmparser::FuncToValidate {
+            resources,
+            index: def.index,
+            ty: def.type_index.index(),
+            features,
+        };
+        func.into_validator(Default::default())
+            .validate(&body)
+            .map_err(|e| Box::new(ValidationError::in_function(&e, &body, def)))
+    }
+}
+
+struct Resources<'c> {
+    checker: &'c Checker,
+    context: &'c Context,
+    data: u32,
+}
+
+impl WasmModuleResources for Resources<'_> {
+    fn table_at(&self, at: u32) -> Option<wasmparser::TableType> {
+        self.checker.resources.table_at(at)
+    }
+
+    fn memory_at(&self, at: u32) -> Option<wasmparser::MemoryType> {
+        self.checker.resources.memory_at(at)
+    }
+
+    fn tag_at(&self, at: u32) -> Option<&wasmparser::FuncType> {
+        self.checker.resources.tag_at(at)
+    }
+
+    fn global_at(&self, at: u32) -> Option<wasmparser::GlobalType> {
+        self.checker.resources.global_at(at)
+    }
+
+    fn sub_type_at(&self, type_index: u32) -> Option<&wasmparser::SubType> {
+        self.checker.resources.sub_type_at(type_index)
+    }
+
+    fn sub_type_at_id(&self, id: wasmparser::types::CoreTypeId) -> &wasmparser::SubType {
+        self.checker.resources.sub_type_at_id(id)
+    }
+
+    fn type_id_of_function(&self, func_idx: u32) -> Option<wasmparser::types::CoreTypeId> {
+        let ty = self.type_index_of_function(func_idx)?;
+        let func = (*self.checker.funcs.get(ty as usize)?)?;
+        self.checker.resources.type_id_of_function(func)
+    }
+
+    fn type_index_of_function(&self, func_index: u32) -> Option<u32> {
+        self.context.funcs.get(func_index as usize).copied()
+    }
+
+    fn element_type_at(&self, at: u32) -> Option<wasmparser::RefType> {
+        *self.checker.elements.get(at as usize)?
+    }
+
+    fn is_subtype(&self, a: wasmparser::ValType, b: wasmparser::ValType) -> bool {
+        self.checker.resources.is_subtype(a, b)
+    }
+
+    fn is_shared(&self, ty: wasmparser::RefType) -> bool {
+        self.checker.resources.is_shared(ty)
+    }
+
+    fn check_heap_type(
+        &self,
+        heap_type: &mut wasmparser::HeapType,
+        offset: usize,
+    ) -> Result<(), wasmparser::BinaryReaderError> {
+        self.checker.resources.check_heap_type(heap_type, offset)
+    }
+
+    fn top_type(&self, heap_type: &wasmparser::HeapType) -> wasmparser::HeapType {
+        self.checker.resources.top_type(heap_type)
+    }
+
+    fn element_count(&self) -> u32 {
+        self.checker.elements.len() as u32
+    }
+
+    fn data_count(&self) -> Option<u32> {
+        Some(self.data)
+    }
+
+    fn is_function_referenced(&self, _idx: u32) -> bool {
+        // `finish` declares every function used with `ref.func`
+        true
+    }
+}
@@ -0,0 +1,64 @@
+use crate::*;
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Types from the module that builders need to intern block types and track the operand stack.
+/// The module extends it as types, functions and globals are added, so it's shared instead of
+/// copied into each function.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Context {
+    pub interned: std::collections::HashMap<InternedType, u32>,
+    /// Types that have been given an index but aren't in the module's type section yet
+    pub pending: Vec<InternedType>,
+    /// The function type at each type index, `None` for other types
+    pub types: Vec<Option<wasm_encoder::FuncType>>,
+    /// The type index of each function handle
+    pub funcs: Vec<u32>,
+    /// The type of each global handle
+    pub globals: Vec<ValType>,
+}
+
+impl Context {
+    /// Give `ty` the next type index, the module encodes it from `pending`
+    pub fn define(&mut self, ty: InternedType) -> u32 {
+        self.types.push(match &ty {
+            InternedType::Func(f) => Some(f.clone()),
+            _ => None,
+        });
+        self.pending.push(ty);
+        self.types.len() as u32 - 1
+    }
+
+    pub fn intern(&mut self, ty: InternedType) -> u32 {
+        if let Some(index) = self.interned.get(&ty) {
+            return *index;
+        }
+        let index = self.define(ty.clone());
+        self.interned.insert(ty, index);
+        index
+    }
+
+    pub fn func_type(&self, index: u32) -> Option<&wasm_encoder::FuncType> {
+        self.types.get(index as usize)?.as_ref()
+    }
+
+    /// The type of the function with handle `index`
+    pub fn signature(&self, index: u32) -> Option<&wasm_encoder::FuncType> {
+        self.func_type(*self.funcs.get(index as usize)?)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SharedContext(Arc<Mutex<Context>>);
+
+impl SharedContext {
+    pub fn lock(&self) -> MutexGuard<'_, Context> {
+        // The context is only appended to, so it's still usable after a panic
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// A copy of the context that isn't shared with this one
+    pub fn detach(&self) -> SharedContext {
+        SharedContext(Arc::new(Mutex::new(self.lock().clone())))
+    }
+}
@@ -0,0 +1,105 @@
+use crate::*;
+
+/// Errors detected while building or finishing a module, see `Module::try_finish`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// An `end` without an open block, or an `else`, `catch`, `catch_all` or `delegate` that
+    /// doesn't belong to the innermost open block
+    UnbalancedBlock {
+        function: String,
+        instr: usize,
+    },
+    /// A block opened at `instr` that is never closed with `end`
+    MissingEnd {
+        function: String,
+        instr: usize,
+    },
+    DuplicateExport(String),
+    /// A handle that doesn't refer to anything defined in the module, `function` and `instr`
+    /// are set when the handle is used by an instruction
+    InvalidIndex {
+        kind: &'static str,
+        index: u32,
+        function: Option<String>,
+        instr: Option<usize>,
+    },
+    ConflictingStart {
+        first: FunctionIndex,
+        second: FunctionIndex,
+    },
+    /// A builder helper was used with a type it doesn't support
+    InvalidType {
+        helper: &'static str,
+        ty: ValType,
+    },
+    /// A builder helper needs a type that isn't known, usually because the builder isn't typed
+    UnknownType {
+        helper: &'static str,
+    },
+    /// A branch to a label whose block isn't open
+    LabelOutOfScope(Label),
+    /// A call made with the wrong number of arguments, for indirect and reference calls
+    /// `expected` includes the callee operand
+    ArgumentCount {
+        function: String,
+        instr: usize,
+        expected: usize,
+        actual: usize,
+    },
+    /// The last function failed to validate when `Module::check_functions` is enabled
+    Validation(Box<ValidationError>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::UnbalancedBlock { function, instr } => write!(
+                f,
+                "Unbalanced block in function `{function}`: instruction {instr} has no matching block"
+            ),
+            Error::MissingEnd { function, instr } => write!(
+                f,
+                "Missing `end` in function `{function}` for the block opened at instruction {instr}"
+            ),
+            Error::DuplicateExport(name) => write!(f, "Duplicate export name: {name}"),
+            Error::InvalidIndex {
+                kind,
+                index,
+                function,
+                instr,
+            } => {
+                write!(f, "Invalid {kind} index {index}")?;
+                if let Some(function) = function {
+                    write!(f, " in function `{function}`")?;
+                }
+                if let Some(instr) = instr {
+                    write!(f, " at instruction {instr}")?;
+                }
+                Ok(())
+            }
+            Error::ConflictingStart { first, second } => write!(
+                f,
+                "Conflicting start functions: {} and {}",
+                first.index(),
+                second.index()
+            ),
+            Error::InvalidType { helper, ty } => write!(f, "Invalid type in `{helper}`: {ty:?}"),
+            Error::UnknownType { helper } => write!(f, "Unknown type in `{helper}`"),
+            Error::ArgumentCount {
+                function,
+                instr,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Call in function `{function}` at instruction {instr} has {actual} arguments, expected {expected}"
+            ),
+            Error::LabelOutOfScope(label) => {
+                write!(f, "Label {} is not in scope", label.index())
+            }
+            Error::Validation(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -4,6 +4,30 @@ pub trait Expr<'a> {
     fn expr(self, builder: &mut Builder<'a>);
 }
 
+/// Arguments for the call helpers, each element is expected to push a single value
+pub trait Args<'a> {
+    const COUNT: usize;
+
+    fn push_args(self, builder: &mut Builder<'a>);
+}
+
+impl<'a> Args<'a> for () {
+    const COUNT: usize = 0;
+
+    fn push_args(self, _builder: &mut Builder<'a>) {}
+}
+
+impl<'a, T: Expr<'a>, const N: usize> Args<'a> for [T; N] {
+    const COUNT: usize = N;
+
+    #[track_caller]
+    fn push_args(self, builder: &mut Builder<'a>) {
+        for arg in self {
+            builder.push(arg);
+        }
+    }
+}
+
 impl<'a, F: FnOnce(&mut Builder<'a>)> Expr<'a> for F {
     fn expr(self, builder: &mut Builder<'a>) {
         self(builder)
@@ -11,61 +35,114 @@ impl<'a, F: FnOnce(&mut Builder<'a>)> Expr<'a> for F {
 }
 
 impl<'a> Expr<'a> for Vec<Instr<'a>> {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.extend(self);
     }
 }
 
 impl<'a, const N: usize> Expr<'a> for [Instr<'a>; N] {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.extend(self);
     }
 }
 
 impl<'a> Expr<'a> for &[Instr<'a>] {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.extend(self.to_vec());
     }
 }
 
 impl<'a> Expr<'a> for Instr<'a> {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push([self]);
     }
 }
 
 impl<'a> Expr<'a> for Builder<'a> {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
+        let offset = builder.instrs.len();
+        builder.label_names.extend(
+            self.label_names
+                .into_iter()
+                .map(|(pos, name)| (pos + offset, name)),
+        );
+        builder.calls.extend(
+            self.calls
+                .into_iter()
+                .map(|(pos, count)| (pos + offset, count)),
+        );
         builder.extend(self.instrs);
+        for (pos, loc) in self.locations {
+            builder.set_location(pos + offset, loc);
+        }
     }
 }
 
 impl<'a> Expr<'a> for bool {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::I32Const(self as i32));
     }
 }
 
 impl<'a> Expr<'a> for i32 {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::I32Const(self));
     }
 }
 
 impl<'a> Expr<'a> for i64 {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::I64Const(self));
     }
 }
 
 impl<'a> Expr<'a> for f32 {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::F32Const(self));
     }
 }
 
 impl<'a> Expr<'a> for f64 {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::F64Const(self));
     }
 }
+
+macro_rules! tuple_expr {
+    ($($name:ident),*) => {
+        impl<'a, $($name: Expr<'a>),*> Expr<'a> for ($($name,)*) {
+            #[track_caller]
+            #[allow(non_snake_case)]
+            fn expr(self, builder: &mut Builder<'a>) {
+                let ($($name,)*) = self;
+                $(builder.push($name);)*
+            }
+        }
+
+        impl<'a, $($name: Expr<'a>),*> Args<'a> for ($($name,)*) {
+            const COUNT: usize = [$(stringify!($name)),*].len();
+
+            #[track_caller]
+            fn push_args(self, builder: &mut Builder<'a>) {
+                builder.push(self);
+            }
+        }
+    };
+}
+
+tuple_expr!(A);
+tuple_expr!(A, B);
+tuple_expr!(A, B, C);
+tuple_expr!(A, B, C, D);
+tuple_expr!(A, B, C, D, E);
+tuple_expr!(A, B, C, D, E, F);
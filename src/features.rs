@@ -0,0 +1,221 @@
+use crate::*;
+
+pub use wasmparser::WasmFeatures;
+
+macro_rules! define_proposal {
+    ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident ($($ann:tt)*) )*) => {
+        /// The proposal that introduced `op`, using the names from `wasmparser::for_each_operator`
+        fn proposal(op: &wasmparser::Operator) -> &'static str {
+            #[allow(unreachable_patterns)]
+            match op {
+                $(wasmparser::Operator::$op { .. } => stringify!($proposal),)*
+                _ => "mvp",
+            }
+        }
+    };
+}
+
+wasmparser::for_each_operator!(define_proposal);
+
+fn proposal_features(proposal: &str) -> WasmFeatures {
+    match proposal {
+        "exceptions" => WasmFeatures::EXCEPTIONS,
+        "legacy_exceptions" => WasmFeatures::LEGACY_EXCEPTIONS,
+        "tail_call" => WasmFeatures::TAIL_CALL,
+        "reference_types" => WasmFeatures::REFERENCE_TYPES,
+        "sign_extension" => WasmFeatures::SIGN_EXTENSION,
+        "saturating_float_to_int" => WasmFeatures::SATURATING_FLOAT_TO_INT,
+        "bulk_memory" => WasmFeatures::BULK_MEMORY,
+        "threads" => WasmFeatures::THREADS,
+        "simd" => WasmFeatures::SIMD,
+        "relaxed_simd" => WasmFeatures::RELAXED_SIMD,
+        "gc" => WasmFeatures::GC,
+        "function_references" => WasmFeatures::FUNCTION_REFERENCES,
+        "memory_control" => WasmFeatures::MEMORY_CONTROL,
+        "stack_switching" => WasmFeatures::STACK_SWITCHING,
+        "wide_arithmetic" => WasmFeatures::WIDE_ARITHMETIC,
+        "shared_everything_threads" => WasmFeatures::SHARED_EVERYTHING_THREADS,
+        _ => WasmFeatures::empty(),
+    }
+}
+
+#[track_caller]
+fn require(
+    features: WasmFeatures,
+    required: WasmFeatures,
+    proposal: &str,
+    what: impl FnOnce() -> String,
+) {
+    if !features.contains(required) {
+        panic!("{} requires the `{proposal}` feature", what());
+    }
+}
+
+/// Panics if `instr` isn't available with `features`
+#[track_caller]
+pub(crate) fn check_instr(features: WasmFeatures, instr: &Instr) {
+    if let Instr::Block(bt) | Instr::Loop(bt) | Instr::If(bt) = instr {
+        if let BlockType::FunctionType(_) = bt {
+            require(features, WasmFeatures::MULTI_VALUE, "multi_value", || {
+                format!("Block type `{bt:?}`")
+            });
+        }
+    }
+
+    with_operator(instr, |op| {
+        let proposal = proposal(op);
+        require(features, proposal_features(proposal), proposal, || {
+            format!("Instruction `{op:?}`")
+        });
+    })
+}
+
+/// Call `f` with the `wasmparser` operator for `instr`
+pub(crate) fn with_operator<R>(instr: &Instr, f: impl FnOnce(&wasmparser::Operator) -> R) -> R {
+    let mut bytes = vec![];
+    wasm_encoder::Encode::encode(instr, &mut bytes);
+    let op = wasmparser::BinaryReader::new(&bytes, 0)
+        .read_operator()
+        .expect("invalid instruction");
+    f(&op)
+}
+
+/// Panics if `ty` isn't available with `features`
+#[track_caller]
+pub(crate) fn check_val_type(features: WasmFeatures, ty: &ValType) {
+    let (required, proposal) = match ty {
+        ValType::V128 => (WasmFeatures::SIMD, "simd"),
+        ValType::Ref(r) if *r == RefType::FUNCREF || *r == RefType::EXTERNREF => {
+            (WasmFeatures::REFERENCE_TYPES, "reference_types")
+        }
+        ValType::Ref(RefType {
+            heap_type: HeapType::Concrete(_),
+            ..
+        }) => (WasmFeatures::FUNCTION_REFERENCES, "function_references"),
+        ValType::Ref(RefType {
+            heap_type:
+                HeapType::Abstract {
+                    ty: wasm_encoder::AbstractHeapType::Exn,
+                    ..
+                },
+            ..
+        }) => (WasmFeatures::EXCEPTIONS, "exceptions"),
+        ValType::Ref(_) => (WasmFeatures::GC, "gc"),
+        _ => return,
+    };
+    require(features, required, proposal, || format!("Type `{ty:?}`"));
+}
+
+/// Panics if a function type with `params` and `results` isn't available with `features`
+#[track_caller]
+pub(crate) fn check_func_type(features: WasmFeatures, params: &[ValType], results: &[ValType]) {
+    for ty in params.iter().chain(results) {
+        check_val_type(features, ty);
+    }
+    if results.len() > 1 {
+        require(features, WasmFeatures::MULTI_VALUE, "multi_value", || {
+            format!("Function type with {} results", results.len())
+        });
+    }
+}
+
+/// Panics if a global of type `ty` isn't available with `features`
+#[track_caller]
+pub(crate) fn check_global_type(features: WasmFeatures, ty: &GlobalType) {
+    check_val_type(features, &ty.val_type);
+    if ty.shared {
+        require(
+            features,
+            WasmFeatures::SHARED_EVERYTHING_THREADS,
+            "shared_everything_threads",
+            || "Shared global".to_string(),
+        );
+    }
+}
+
+/// Panics if a memory of type `ty` isn't available with `features`
+#[track_caller]
+pub(crate) fn check_memory_type(features: WasmFeatures, ty: &MemoryType) {
+    if ty.memory64 {
+        require(features, WasmFeatures::MEMORY64, "memory64", || {
+            "64-bit memory".to_string()
+        });
+    }
+    if ty.shared {
+        require(features, WasmFeatures::THREADS, "threads", || {
+            "Shared memory".to_string()
+        });
+    }
+    if ty.page_size_log2.is_some() {
+        require(
+            features,
+            WasmFeatures::CUSTOM_PAGE_SIZES,
+            "custom_page_sizes",
+            || "Custom page size".to_string(),
+        );
+    }
+}
+
+/// Panics if a table of type `ty` isn't available with `features`, `funcref` tables are
+/// available without reference types
+#[track_caller]
+pub(crate) fn check_table_type(features: WasmFeatures, ty: &TableType) {
+    if ty.element_type != RefType::FUNCREF {
+        check_val_type(features, &ValType::Ref(ty.element_type));
+    }
+    if ty.table64 {
+        require(features, WasmFeatures::MEMORY64, "memory64", || {
+            "64-bit table".to_string()
+        });
+    }
+    if ty.shared {
+        require(
+            features,
+            WasmFeatures::SHARED_EVERYTHING_THREADS,
+            "shared_everything_threads",
+            || "Shared table".to_string(),
+        );
+    }
+}
+
+/// Panics if GC types (structs, arrays and rec groups) aren't available with `features`
+#[track_caller]
+pub(crate) fn check_gc_type(features: WasmFeatures, what: &str) {
+    require(features, WasmFeatures::GC, "gc", || what.to_string());
+}
+
+/// Encode the `target_features` custom section used by the tool conventions to describe
+/// which features a module uses
+pub(crate) fn target_features(features: WasmFeatures) -> CustomSection<'static> {
+    const NAMES: &[(WasmFeatures, &str)] = &[
+        (WasmFeatures::THREADS, "atomics"),
+        (WasmFeatures::BULK_MEMORY, "bulk-memory"),
+        (WasmFeatures::EXCEPTIONS, "exception-handling"),
+        (WasmFeatures::EXTENDED_CONST, "extended-const"),
+        (WasmFeatures::GC, "gc"),
+        (WasmFeatures::MEMORY64, "memory64"),
+        (WasmFeatures::MULTI_MEMORY, "multimemory"),
+        (WasmFeatures::MULTI_VALUE, "multivalue"),
+        (WasmFeatures::MUTABLE_GLOBAL, "mutable-globals"),
+        (WasmFeatures::SATURATING_FLOAT_TO_INT, "nontrapping-fptoint"),
+        (WasmFeatures::REFERENCE_TYPES, "reference-types"),
+        (WasmFeatures::RELAXED_SIMD, "relaxed-simd"),
+        (WasmFeatures::SIGN_EXTENSION, "sign-ext"),
+        (WasmFeatures::SIMD, "simd128"),
+        (WasmFeatures::TAIL_CALL, "tail-call"),
+    ];
+    let enabled = NAMES
+        .iter()
+        .filter(|(f, _)| features.contains(*f))
+        .collect::<Vec<_>>();
+    let mut data = vec![];
+    wasm_encoder::Encode::encode(&(enabled.len() as u32), &mut data);
+    for (_, name) in enabled {
+        data.push(b'+');
+        wasm_encoder::Encode::encode(*name, &mut data);
+    }
+    CustomSection {
+        name: "target_features".into(),
+        data: data.into(),
+    }
+}
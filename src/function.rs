@@ -4,18 +4,36 @@ use crate::*;
 pub struct Function<'a> {
     pub name: String,
     pub body: Builder<'a>,
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
     pub locals: Vec<ValType>,
+    pub local_names: std::collections::BTreeMap<u32, String>,
     pub type_index: FunctionTypeIndex,
     pub index: u32,
     pub export: Option<String>,
 }
 
 impl<'a> Function<'a> {
+    #[track_caller]
     pub fn push(&mut self, expr: impl Expr<'a>) -> &mut Self {
         self.body.push(expr);
         self
     }
 
+    #[track_caller]
+    pub fn throw(&mut self, tag: TagIndex) -> &mut Self {
+        self.body.throw(tag);
+        self
+    }
+
+    /// Push `values` and return them, tuples of expressions can be used to return multiple
+    /// values
+    #[track_caller]
+    pub fn return_values(&mut self, values: impl Expr<'a>) -> &mut Self {
+        self.body.push(values).push(Instr::Return);
+        self
+    }
+
     pub fn export(&mut self, name: impl Into<String>) -> &mut Self {
         self.export = Some(name.into());
         self
@@ -33,4 +51,50 @@ impl<'a> Function<'a> {
     pub fn index(&self) -> FunctionIndex {
         FunctionIndex::from(self.index)
     }
+
+    pub(crate) fn encode_body(&self) -> wasm_encoder::Function {
+        let mut f = wasm_encoder::Function::new_with_locals_types(self.locals.iter().copied());
+        for instr in &self.body.instrs {
+            f.instruction(instr);
+        }
+        f.instruction(&Instr::End);
+        f
+    }
+
+    pub fn param(&self, n: u32) -> Param {
+        assert!(
+            (n as usize) < self.params.len(),
+            "Invalid parameter {n} for function `{}`",
+            self.name
+        );
+        Param::from(n)
+    }
+
+    pub fn local(&mut self, ty: ValType) -> Local {
+        self.locals.push(ty);
+        if let Some(stack) = &mut self.body.stack {
+            stack.add_local(ty);
+        }
+        Local::from((self.params.len() + self.locals.len() - 1) as u32)
+    }
+
+    pub fn local_named(&mut self, ty: ValType, name: impl Into<String>) -> Local {
+        let local = self.local(ty);
+        self.name_local(local, name);
+        local
+    }
+
+    pub fn name_local(&mut self, index: impl Into<u32>, name: impl Into<String>) -> &mut Self {
+        self.local_names.insert(index.into(), name.into());
+        self
+    }
+
+    pub fn local_type(&self, index: u32) -> Option<ValType> {
+        let index = index as usize;
+        if index < self.params.len() {
+            Some(self.params[index])
+        } else {
+            self.locals.get(index - self.params.len()).copied()
+        }
+    }
 }
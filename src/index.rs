@@ -29,11 +29,236 @@ impl<T> Index<T> {
     }
 }
 
+/// Handles that can be exported from a module
+pub trait Export {
+    const KIND: ExportKind;
+}
+
+impl<T: Export> Index<T> {
+    pub fn export(self, module: &mut Module, name: impl Into<String>) -> Self {
+        module.export(name, T::KIND, self.0);
+        self
+    }
+}
+
 handle!(FunctionHandle);
 pub type FunctionIndex = Index<FunctionHandle>;
 
+impl Export for FunctionHandle {
+    const KIND: ExportKind = ExportKind::Func;
+}
+
+impl FunctionIndex {
+    pub fn ref_func<'a>(&self) -> impl Expr<'a> {
+        Instr::RefFunc(self.0)
+    }
+
+    /// Call the function with `args`, the number of arguments is checked against the
+    /// function's type by `Module::try_finish`
+    pub fn call<'a>(self, args: impl Args<'a>) -> impl Expr<'a> {
+        move |builder: &mut Builder<'a>| {
+            builder.call(self, args);
+        }
+    }
+
+    pub fn return_call<'a>(self, args: impl Args<'a>) -> impl Expr<'a> {
+        move |builder: &mut Builder<'a>| {
+            builder.return_call(self, args);
+        }
+    }
+}
+
 impl<'a> Expr<'a> for FunctionIndex {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::Call(self.0));
     }
 }
+
+/// Tracks the handles allocated for one index space (functions, globals, ...)
+/// in declaration order. Imported entities always come first in the final
+/// module, so handles are only resolved to real indices in `Module::finish`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IndexSpace {
+    imported: Vec<bool>,
+}
+
+impl IndexSpace {
+    pub fn import(&mut self) -> u32 {
+        self.imported.push(true);
+        self.imported.len() as u32 - 1
+    }
+
+    pub fn define(&mut self) -> u32 {
+        self.imported.push(false);
+        self.imported.len() as u32 - 1
+    }
+
+    pub fn len(&self) -> u32 {
+        self.imported.len() as u32
+    }
+
+    pub fn resolve(&self) -> Vec<u32> {
+        let num_imports = self.imported.iter().filter(|x| **x).count() as u32;
+        let (mut imports, mut defs) = (0, 0);
+        self.imported
+            .iter()
+            .map(|imported| {
+                if *imported {
+                    imports += 1;
+                    imports - 1
+                } else {
+                    defs += 1;
+                    num_imports + defs - 1
+                }
+            })
+            .collect()
+    }
+}
+
+/// Rewrites handles to final indices in an encoded module
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Resolver {
+    pub funcs: Vec<u32>,
+    pub globals: Vec<u32>,
+    pub memories: Vec<u32>,
+    pub tables: Vec<u32>,
+    pub tags: Vec<u32>,
+}
+
+#[track_caller]
+fn lookup(map: &[u32], kind: &str, index: u32) -> u32 {
+    match map.get(index as usize) {
+        Some(index) => *index,
+        None => panic!("Invalid {kind} index {index}, use `Module::try_finish` to check handles"),
+    }
+}
+
+impl Resolver {
+    pub fn is_identity(&self) -> bool {
+        [
+            &self.funcs,
+            &self.globals,
+            &self.memories,
+            &self.tables,
+            &self.tags,
+        ]
+        .iter()
+        .all(|map| map.iter().enumerate().all(|(i, x)| i as u32 == *x))
+    }
+
+    pub fn function(&self, index: u32) -> u32 {
+        lookup(&self.funcs, "function", index)
+    }
+
+    pub fn global(&self, index: u32) -> u32 {
+        lookup(&self.globals, "global", index)
+    }
+
+    pub fn memory(&self, index: u32) -> u32 {
+        lookup(&self.memories, "memory", index)
+    }
+
+    pub fn table(&self, index: u32) -> u32 {
+        lookup(&self.tables, "table", index)
+    }
+
+    pub fn tag(&self, index: u32) -> u32 {
+        lookup(&self.tags, "tag", index)
+    }
+
+    pub fn reencode(
+        &mut self,
+        data: &[u8],
+    ) -> Result<wasm_encoder::Module, wasm_encoder::reencode::Error> {
+        let mut module = wasm_encoder::Module::new();
+        wasm_encoder::reencode::Reencode::parse_core_module(
+            self,
+            &mut module,
+            wasmparser::Parser::new(0),
+            data,
+        )?;
+        Ok(module)
+    }
+}
+
+impl wasm_encoder::reencode::Reencode for Resolver {
+    type Error = std::convert::Infallible;
+
+    fn function_index(&mut self, func: u32) -> u32 {
+        self.function(func)
+    }
+
+    fn global_index(&mut self, global: u32) -> u32 {
+        self.global(global)
+    }
+
+    fn memory_index(&mut self, memory: u32) -> u32 {
+        self.memory(memory)
+    }
+
+    fn table_index(&mut self, table: u32) -> u32 {
+        self.table(table)
+    }
+
+    fn tag_index(&mut self, tag: u32) -> u32 {
+        self.tag(tag)
+    }
+}
+
+/// Collects the handles an instruction refers to along with their kind, locals and labels
+/// aren't included
+#[derive(Debug, Default)]
+pub(crate) struct Indices(pub Vec<(&'static str, u32)>);
+
+impl Indices {
+    pub fn of(instr: &Instr) -> Vec<(&'static str, u32)> {
+        let mut indices = Indices::default();
+        features::with_operator(instr, |op| {
+            wasm_encoder::reencode::Reencode::instruction(&mut indices, op.clone())
+                .expect("invalid instruction");
+        });
+        indices.0
+    }
+
+    fn add(&mut self, kind: &'static str, index: u32) -> u32 {
+        self.0.push((kind, index));
+        index
+    }
+}
+
+impl wasm_encoder::reencode::Reencode for Indices {
+    type Error = std::convert::Infallible;
+
+    fn data_index(&mut self, data: u32) -> u32 {
+        self.add("data", data)
+    }
+
+    fn element_index(&mut self, element: u32) -> u32 {
+        self.add("element", element)
+    }
+
+    fn function_index(&mut self, func: u32) -> u32 {
+        self.add("function", func)
+    }
+
+    fn global_index(&mut self, global: u32) -> u32 {
+        self.add("global", global)
+    }
+
+    fn memory_index(&mut self, memory: u32) -> u32 {
+        self.add("memory", memory)
+    }
+
+    fn table_index(&mut self, table: u32) -> u32 {
+        self.add("table", table)
+    }
+
+    fn tag_index(&mut self, tag: u32) -> u32 {
+        self.add("tag", tag)
+    }
+
+    fn type_index(&mut self, ty: u32) -> u32 {
+        self.add("type", ty)
+    }
+}
@@ -1,20 +1,40 @@
 mod builder;
+mod check;
+mod context;
+mod error;
 mod expr;
+mod features;
 mod function;
 mod index;
 pub mod link;
+mod names;
+mod rec_group;
+mod source_map;
+mod stack;
 mod type_list;
-
-pub use builder::Builder;
-pub use expr::Expr;
+mod typed_expr;
+mod validation_error;
+
+pub use builder::{BlockSignature, Builder, Case, Label};
+use check::{Checker, Entities};
+use context::SharedContext;
+pub use error::Error;
+pub use expr::{Args, Expr};
+pub use features::WasmFeatures;
 pub use function::Function;
-pub use index::{FunctionIndex, Index};
+pub use index::{Export, FunctionHandle, FunctionIndex, Index};
+use index::{IndexSpace, Indices, Resolver};
+use names::{indirect_name_map, label_names, name_map, Names};
+pub use rec_group::RecGroup;
+pub use stack::{Frame, FrameKind, Stack};
 pub use type_list::{Local, Param, TypeList};
+pub use typed_expr::{F32Expr, F64Expr, I32Expr, I64Expr};
+pub use validation_error::ValidationError;
 
 pub use wasm_encoder::{
-    self as encoder, BlockType, ConstExpr, ElementMode, ElementSection, ElementSegment, Elements,
-    FieldType, HeapType, Instruction as Instr, MemArg, MemoryType, RefType, StorageType, TableType,
-    ValType,
+    self as encoder, BlockType, Catch, ConstExpr, CustomSection, ElementMode, ElementSection,
+    ElementSegment, Elements, ExportKind, FieldType, GlobalType, HeapType, Instruction as Instr,
+    MemArg, MemoryType, RefType, SectionId, StorageType, TableType, ValType,
 };
 
 pub use wasmparser as parser;
@@ -22,16 +42,241 @@ pub use wasmparser as parser;
 handle!(GlobalHandle);
 pub type GlobalIndex = Index<GlobalHandle>;
 
+impl GlobalIndex {
+    pub fn set<'a>(&self) -> impl Expr<'a> {
+        Instr::GlobalSet(self.index())
+    }
+}
+
+impl<'a> Expr<'a> for GlobalIndex {
+    #[track_caller]
+    fn expr(self, builder: &mut Builder<'a>) {
+        builder.push(Instr::GlobalGet(self.index()));
+    }
+}
+
+impl Export for GlobalHandle {
+    const KIND: ExportKind = ExportKind::Global;
+}
+
 handle!(DataSegmentHandle);
-pub type DataSegmentIndex = Index<GlobalHandle>;
+pub type DataSegmentIndex = Index<DataSegmentHandle>;
+
+impl DataSegmentIndex {
+    pub fn data_drop<'a>(&self) -> impl Expr<'a> {
+        Instr::DataDrop(self.index())
+    }
+}
 
 handle!(MemoryHandle);
 pub type MemoryIndex = Index<MemoryHandle>;
 
+impl Export for MemoryHandle {
+    const KIND: ExportKind = ExportKind::Memory;
+}
+
+impl MemoryIndex {
+    pub fn memory_size<'a>(&self) -> impl Expr<'a> {
+        Instr::MemorySize(self.index())
+    }
+
+    pub fn memory_grow<'a>(&self) -> impl Expr<'a> {
+        Instr::MemoryGrow(self.index())
+    }
+
+    pub fn memory_init<'a>(&self, data: DataSegmentIndex) -> impl Expr<'a> {
+        Instr::MemoryInit {
+            mem: self.index(),
+            data_index: data.index(),
+        }
+    }
+
+    pub fn memory_copy<'a>(&self, src: MemoryIndex) -> impl Expr<'a> {
+        Instr::MemoryCopy {
+            src_mem: src.index(),
+            dst_mem: self.index(),
+        }
+    }
+
+    pub fn memory_fill<'a>(&self) -> impl Expr<'a> {
+        Instr::MemoryFill(self.index())
+    }
+}
+
+/// Loads and stores with natural alignment, the address can be an `i32` or an `i64` for
+/// memory64 memories
+macro_rules! memory_access {
+    (
+        loads { $($load:ident => $load_instr:ident, $load_bytes:literal;)* }
+        stores { $($store:ident => $store_instr:ident, $store_bytes:literal;)* }
+    ) => {
+        impl MemoryIndex {
+            fn memarg(&self, offset: u64, bytes: u32) -> MemArg {
+                MemArg {
+                    offset,
+                    align: bytes.trailing_zeros(),
+                    memory_index: self.index(),
+                }
+            }
+
+            $(
+                pub fn $load<'a>(&self, addr: impl Expr<'a>, offset: u64) -> impl Expr<'a> {
+                    let memarg = self.memarg(offset, $load_bytes);
+                    move |builder: &mut Builder<'a>| {
+                        builder.push(addr).push(Instr::$load_instr(memarg));
+                    }
+                }
+            )*
+
+            $(
+                pub fn $store<'a>(
+                    &self,
+                    addr: impl Expr<'a>,
+                    value: impl Expr<'a>,
+                    offset: u64,
+                ) -> impl Expr<'a> {
+                    let memarg = self.memarg(offset, $store_bytes);
+                    move |builder: &mut Builder<'a>| {
+                        builder.push(addr).push(value).push(Instr::$store_instr(memarg));
+                    }
+                }
+            )*
+        }
+    };
+}
+
+memory_access! {
+    loads {
+        load_i32 => I32Load, 4;
+        load_i64 => I64Load, 8;
+        load_f32 => F32Load, 4;
+        load_f64 => F64Load, 8;
+        load_v128 => V128Load, 16;
+        load_s8 => I32Load8S, 1;
+        load_u8 => I32Load8U, 1;
+        load_s16 => I32Load16S, 2;
+        load_u16 => I32Load16U, 2;
+        load_i64_s8 => I64Load8S, 1;
+        load_i64_u8 => I64Load8U, 1;
+        load_i64_s16 => I64Load16S, 2;
+        load_i64_u16 => I64Load16U, 2;
+        load_i64_s32 => I64Load32S, 4;
+        load_i64_u32 => I64Load32U, 4;
+    }
+    stores {
+        store_i32 => I32Store, 4;
+        store_i64 => I64Store, 8;
+        store_f32 => F32Store, 4;
+        store_f64 => F64Store, 8;
+        store_v128 => V128Store, 16;
+        store_u8 => I32Store8, 1;
+        store_u16 => I32Store16, 2;
+        store_i64_u8 => I64Store8, 1;
+        store_i64_u16 => I64Store16, 2;
+        store_i64_u32 => I64Store32, 4;
+    }
+}
+
+handle!(TableHandle);
+pub type TableIndex = Index<TableHandle>;
+
+impl Export for TableHandle {
+    const KIND: ExportKind = ExportKind::Table;
+}
+
+impl TableIndex {
+    pub fn table_get<'a>(&self) -> impl Expr<'a> {
+        Instr::TableGet(self.index())
+    }
+
+    pub fn table_set<'a>(&self) -> impl Expr<'a> {
+        Instr::TableSet(self.index())
+    }
+
+    pub fn table_size<'a>(&self) -> impl Expr<'a> {
+        Instr::TableSize(self.index())
+    }
+
+    pub fn table_grow<'a>(&self) -> impl Expr<'a> {
+        Instr::TableGrow(self.index())
+    }
+
+    pub fn table_fill<'a>(&self) -> impl Expr<'a> {
+        Instr::TableFill(self.index())
+    }
+
+    pub fn table_copy<'a>(&self, src: TableIndex) -> impl Expr<'a> {
+        Instr::TableCopy {
+            src_table: src.index(),
+            dst_table: self.index(),
+        }
+    }
+
+    pub fn table_init<'a>(&self, elem: ElementSegmentIndex) -> impl Expr<'a> {
+        Instr::TableInit {
+            elem_index: elem.index(),
+            table: self.index(),
+        }
+    }
+}
+
+handle!(ElementSegmentHandle);
+pub type ElementSegmentIndex = Index<ElementSegmentHandle>;
+
+impl ElementSegmentIndex {
+    pub fn elem_drop<'a>(&self) -> impl Expr<'a> {
+        Instr::ElemDrop(self.index())
+    }
+}
+
+handle!(TagHandle);
+pub type TagIndex = Index<TagHandle>;
+
+impl Export for TagHandle {
+    const KIND: ExportKind = ExportKind::Tag;
+}
+
+impl TagIndex {
+    pub fn throw<'a>(&self) -> impl Expr<'a> {
+        Instr::Throw(self.index())
+    }
+
+    pub fn catch(&self, label: u32) -> Catch {
+        Catch::One {
+            tag: self.index(),
+            label,
+        }
+    }
+
+    pub fn catch_ref(&self, label: u32) -> Catch {
+        Catch::OneRef {
+            tag: self.index(),
+            label,
+        }
+    }
+
+    /// Like `catch`, but branches to `label`. `builder` is the one the `try_table` is pushed to,
+    /// the depth is taken from its current position.
+    #[track_caller]
+    pub fn catch_label(&self, builder: &Builder, label: Label) -> Catch {
+        self.catch(builder.depth_or_panic(label))
+    }
+
+    /// Like `catch_ref`, but branches to `label`, see `catch_label`
+    #[track_caller]
+    pub fn catch_ref_label(&self, builder: &Builder, label: Label) -> Catch {
+        self.catch_ref(builder.depth_or_panic(label))
+    }
+}
+
 handle!(FunctionTypeHandle);
 pub type FunctionTypeIndex = Index<FunctionTypeHandle>;
 
 impl FunctionTypeIndex {
+    pub fn block_type(self) -> BlockType {
+        BlockType::FunctionType(self.index())
+    }
+
     pub fn ref_type(self, nullable: bool) -> RefType {
         RefType {
             nullable,
@@ -82,6 +327,56 @@ impl ArrayTypeIndex {
     pub fn array_set<'a>(&self) -> impl Expr<'a> {
         Instr::ArraySet(self.index())
     }
+
+    pub fn array_new_fixed<'a>(&self, size: u32) -> impl Expr<'a> {
+        Instr::ArrayNewFixed {
+            array_type_index: self.index(),
+            array_size: size,
+        }
+    }
+
+    pub fn array_new_data<'a>(&self, data: DataSegmentIndex) -> impl Expr<'a> {
+        Instr::ArrayNewData {
+            array_type_index: self.index(),
+            array_data_index: data.index(),
+        }
+    }
+
+    pub fn array_new_elem<'a>(&self, elem: ElementSegmentIndex) -> impl Expr<'a> {
+        Instr::ArrayNewElem {
+            array_type_index: self.index(),
+            array_elem_index: elem.index(),
+        }
+    }
+
+    pub fn array_len<'a>(&self) -> impl Expr<'a> {
+        Instr::ArrayLen
+    }
+
+    pub fn array_fill<'a>(&self) -> impl Expr<'a> {
+        Instr::ArrayFill(self.index())
+    }
+
+    pub fn array_copy<'a>(&self, src: ArrayTypeIndex) -> impl Expr<'a> {
+        Instr::ArrayCopy {
+            array_type_index_dst: self.index(),
+            array_type_index_src: src.index(),
+        }
+    }
+
+    pub fn array_init_data<'a>(&self, data: DataSegmentIndex) -> impl Expr<'a> {
+        Instr::ArrayInitData {
+            array_type_index: self.index(),
+            array_data_index: data.index(),
+        }
+    }
+
+    pub fn array_init_elem<'a>(&self, elem: ElementSegmentIndex) -> impl Expr<'a> {
+        Instr::ArrayInitElem {
+            array_type_index: self.index(),
+            array_elem_index: elem.index(),
+        }
+    }
 }
 
 handle!(StructTypeHandle);
@@ -136,42 +431,140 @@ impl StructTypeIndex {
     }
 }
 
-#[derive(Clone, Default)]
+#[derive(Default)]
 pub struct Module<'a> {
     types: wasm_encoder::TypeSection,
+    /// Function, global and type information shared with the builders of typed functions
+    context: SharedContext,
     globals: wasm_encoder::GlobalSection,
     funcs: wasm_encoder::FunctionSection,
-    func_names: wasm_encoder::NameMap,
-    global_names: wasm_encoder::NameMap,
+    global_names: Vec<(String, u32)>,
     code: wasm_encoder::CodeSection,
-    names: wasm_encoder::NameSection,
-    exports: wasm_encoder::ExportSection,
+    names: Names,
+    exports: Vec<(String, ExportKind, u32)>,
     data: wasm_encoder::DataSection,
     memory: wasm_encoder::MemorySection,
     import_info: Vec<(String, u32)>,
+    func_space: IndexSpace,
+    global_space: IndexSpace,
+    memory_space: IndexSpace,
+    table_space: IndexSpace,
+    tag_space: IndexSpace,
     defs: Vec<Function<'a>>,
     global_defs: Vec<Global>,
     start: Option<FunctionIndex>,
+    start_conflict: Option<(FunctionIndex, FunctionIndex)>,
     imports: wasm_encoder::ImportSection,
     tables: wasm_encoder::TableSection,
+    tags: wasm_encoder::TagSection,
     elements: wasm_encoder::ElementSection,
+    element_funcs: std::collections::BTreeSet<u32>,
+    custom_sections: Vec<(Placement, CustomSection<'static>)>,
+    producers: Option<std::collections::BTreeMap<String, Vec<(String, String)>>>,
+    check_functions: bool,
+    features: Option<WasmFeatures>,
+    typed: bool,
+    /// Types of the tables, memories, globals, tags and element segments for `check`
+    entities: Entities,
+    checker: Option<std::sync::Arc<Checker>>,
+}
+
+impl Clone for Module<'_> {
+    fn clone(&self) -> Self {
+        // The clone gets its own context, otherwise types and functions added to one module
+        // would show up in the other
+        let context = self.context.detach();
+        let mut defs = self.defs.clone();
+        for def in &mut defs {
+            def.body.set_context(&context);
+        }
+        Module {
+            context,
+            defs,
+            types: self.types.clone(),
+            globals: self.globals.clone(),
+            funcs: self.funcs.clone(),
+            global_names: self.global_names.clone(),
+            code: self.code.clone(),
+            names: self.names.clone(),
+            exports: self.exports.clone(),
+            data: self.data.clone(),
+            memory: self.memory.clone(),
+            import_info: self.import_info.clone(),
+            func_space: self.func_space.clone(),
+            global_space: self.global_space.clone(),
+            memory_space: self.memory_space.clone(),
+            table_space: self.table_space.clone(),
+            tag_space: self.tag_space.clone(),
+            global_defs: self.global_defs.clone(),
+            start: self.start,
+            start_conflict: self.start_conflict,
+            imports: self.imports.clone(),
+            tables: self.tables.clone(),
+            tags: self.tags.clone(),
+            elements: self.elements.clone(),
+            element_funcs: self.element_funcs.clone(),
+            custom_sections: self.custom_sections.clone(),
+            producers: self.producers.clone(),
+            check_functions: self.check_functions,
+            features: self.features,
+            typed: self.typed,
+            entities: self.entities.clone(),
+            checker: self.checker.clone(),
+        }
+    }
+}
+
+/// Where a custom section is written relative to the standard sections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Placement {
+    Before(SectionId),
+    After(SectionId),
+    End,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum InternedType {
+    Func(wasm_encoder::FuncType),
+    Struct(Vec<FieldType>),
+    Array(FieldType),
 }
 
-pub struct Types<'a>(pub &'a mut wasm_encoder::TypeSection);
+/// Adds types that the module doesn't intern, the section isn't exposed so every type is
+/// counted when later type indices are handed out
+pub struct Types<'a>(&'a mut wasm_encoder::TypeSection, &'a SharedContext);
 
 impl<'a> Types<'a> {
+    #[track_caller]
     pub fn push<F: FnOnce(wasm_encoder::CoreTypeEncoder)>(&mut self, f: F) -> u32 {
+        let len = self.0.len();
         f(self.0.ty());
-        self.0.len() - 1
+        assert_eq!(
+            self.0.len(),
+            len + 1,
+            "`Types::push` has to encode one type"
+        );
+        let mut context = self.1.lock();
+        context.types.push(None);
+        context.types.len() as u32 - 1
     }
 }
 
-pub struct Tables<'a>(pub &'a mut wasm_encoder::TableSection);
+/// Adds tables to the module, each one gets a handle from the table index space
+pub struct Tables<'a>(
+    &'a mut wasm_encoder::TableSection,
+    &'a mut IndexSpace,
+    &'a mut Vec<TableType>,
+    WasmFeatures,
+);
 
 impl<'a> Tables<'a> {
-    pub fn push(&mut self, ty: TableType) -> u32 {
+    #[track_caller]
+    pub fn push(&mut self, ty: TableType) -> TableIndex {
+        features::check_table_type(self.3, &ty);
         self.0.table(ty);
-        self.0.len() - 1
+        self.2.push(ty);
+        TableIndex::from(self.1.define())
     }
 }
 
@@ -182,6 +575,7 @@ pub struct Global {
 }
 
 impl<'a> Expr<'a> for Global {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::GlobalGet(self.index));
     }
@@ -221,11 +615,71 @@ pub struct ArrayType {
     pub mutable: bool,
 }
 
+impl From<FieldType> for ArrayType {
+    fn from(value: FieldType) -> Self {
+        ArrayType {
+            item: value.element_type,
+            mutable: value.mutable,
+        }
+    }
+}
+
 impl<'a> Module<'a> {
     pub fn new() -> Self {
         Self::default()
     }
 
+    pub fn with_features(features: WasmFeatures) -> Self {
+        let mut module = Self::new();
+        module.features(features);
+        module
+    }
+
+    /// A module limited to the WebAssembly 1.0 feature set
+    pub fn mvp() -> Self {
+        Self::with_features(WasmFeatures::WASM1)
+    }
+
+    /// A module limited to the WebAssembly 2.0 feature set
+    pub fn wasm2() -> Self {
+        Self::with_features(WasmFeatures::WASM2)
+    }
+
+    /// A module limited to the WebAssembly 3.0 feature set
+    pub fn wasm3() -> Self {
+        Self::with_features(WasmFeatures::WASM3)
+    }
+
+    /// Restrict the module to `features`, this is used for validation, to reject instructions
+    /// and types as they're added and to emit a `target_features` section. By default all
+    /// features are enabled and no `target_features` section is emitted.
+    ///
+    /// Nothing that's already been added is checked again, so this has to be called before
+    /// anything is added to the module.
+    #[track_caller]
+    pub fn features(&mut self, features: WasmFeatures) -> &mut Self {
+        let empty = self.types.is_empty()
+            && self.context.lock().types.is_empty()
+            && self.func_space.len() == 0
+            && self.global_space.len() == 0
+            && self.memory_space.len() == 0
+            && self.table_space.len() == 0
+            && self.tag_space.len() == 0
+            && self.entities.elements.is_empty()
+            && self.data.is_empty();
+        assert!(
+            empty,
+            "`Module::features` has to be called before anything is added to the module"
+        );
+        self.features = Some(features);
+        self
+    }
+
+    pub fn enabled_features(&self) -> WasmFeatures {
+        self.features.unwrap_or_else(WasmFeatures::all)
+    }
+
+    #[track_caller]
     pub fn global(
         &mut self,
         name: impl AsRef<str>,
@@ -234,16 +688,17 @@ impl<'a> Module<'a> {
         shared: bool,
         init: &ConstExpr,
     ) -> &mut Global {
-        self.globals.global(
-            wasm_encoder::GlobalType {
-                shared,
-                val_type: ty,
-                mutable,
-            },
-            &init,
-        );
-        let index = self.globals.len() - 1;
-        self.global_names.append(index, name.as_ref());
+        let global_type = wasm_encoder::GlobalType {
+            shared,
+            val_type: ty,
+            mutable,
+        };
+        features::check_global_type(self.enabled_features(), &global_type);
+        self.globals.global(global_type, init);
+        let index = self.global_space.define();
+        self.context.lock().globals.push(ty);
+        self.entities.globals.push(global_type);
+        self.global_names.push((name.as_ref().to_string(), index));
         self.global_defs.push(Global {
             index,
             export: None,
@@ -261,24 +716,149 @@ impl<'a> Module<'a> {
     ) -> FunctionIndex {
         let params = params.into_iter().collect::<Vec<_>>();
         let results = results.into_iter().collect::<Vec<_>>();
-        self.types.ty().function(params.clone(), results.clone());
-        let type_index = self.types.len() - 1;
+        let type_index = self.func_type(params, results).index();
         self.imports.import(
             module.as_ref(),
             name.as_ref(),
             wasm_encoder::EntityType::Function(type_index),
         );
-        let idx = self.imports.len() - 1;
+        let idx = self.func_space.import();
+        self.context.lock().funcs.push(type_index);
         self.import_info
             .push((func_name.unwrap_or(name.as_ref()).to_string(), idx));
         FunctionIndex::from(idx)
     }
 
+    #[track_caller]
+    pub fn import_memory(
+        &mut self,
+        module: impl AsRef<str>,
+        name: impl AsRef<str>,
+        mt: MemoryType,
+    ) -> MemoryIndex {
+        features::check_memory_type(self.enabled_features(), &mt);
+        self.imports.import(module.as_ref(), name.as_ref(), mt);
+        self.entities.memories.push(mt);
+        MemoryIndex::from(self.memory_space.import())
+    }
+
+    #[track_caller]
+    pub fn import_table(
+        &mut self,
+        module: impl AsRef<str>,
+        name: impl AsRef<str>,
+        ty: TableType,
+    ) -> TableIndex {
+        features::check_table_type(self.enabled_features(), &ty);
+        self.imports.import(module.as_ref(), name.as_ref(), ty);
+        self.entities.tables.push(ty);
+        TableIndex::from(self.table_space.import())
+    }
+
+    #[track_caller]
+    pub fn import_global(
+        &mut self,
+        module: impl AsRef<str>,
+        name: impl AsRef<str>,
+        global_name: Option<&str>,
+        ty: GlobalType,
+    ) -> GlobalIndex {
+        features::check_global_type(self.enabled_features(), &ty);
+        self.imports.import(module.as_ref(), name.as_ref(), ty);
+        let index = self.global_space.import();
+        self.context.lock().globals.push(ty.val_type);
+        self.entities.globals.push(ty);
+        self.global_names
+            .push((global_name.unwrap_or(name.as_ref()).to_string(), index));
+        GlobalIndex::from(index)
+    }
+
+    pub fn import_tag(
+        &mut self,
+        module: impl AsRef<str>,
+        name: impl AsRef<str>,
+        params: impl IntoIterator<Item = ValType>,
+    ) -> TagIndex {
+        let type_index = self.func_type(params, []).index();
+        self.imports.import(
+            module.as_ref(),
+            name.as_ref(),
+            wasm_encoder::TagType {
+                kind: wasm_encoder::TagKind::Exception,
+                func_type_idx: type_index,
+            },
+        );
+        self.entities.tags.push(type_index);
+        TagIndex::from(self.tag_space.import())
+    }
+
+    pub fn export(
+        &mut self,
+        name: impl Into<String>,
+        kind: ExportKind,
+        index: impl Into<u32>,
+    ) -> &mut Self {
+        self.exports.push((name.into(), kind, index.into()));
+        self
+    }
+
+    pub fn tag(&mut self, params: impl IntoIterator<Item = ValType>) -> TagIndex {
+        let type_index = self.func_type(params, []).index();
+        self.tags.tag(wasm_encoder::TagType {
+            kind: wasm_encoder::TagKind::Exception,
+            func_type_idx: type_index,
+        });
+        self.entities.tags.push(type_index);
+        TagIndex::from(self.tag_space.define())
+    }
+
+    pub fn custom_section(
+        &mut self,
+        placement: Placement,
+        name: impl Into<String>,
+        data: impl Into<Vec<u8>>,
+    ) -> &mut Self {
+        self.custom_sections.push((
+            placement,
+            CustomSection {
+                name: name.into().into(),
+                data: data.into().into(),
+            },
+        ));
+        self
+    }
+
+    pub fn producers(&mut self) -> &mut Self {
+        self.producers.get_or_insert_with(Default::default);
+        self
+    }
+
+    pub fn producer(
+        &mut self,
+        field: impl Into<String>,
+        name: impl Into<String>,
+        version: impl Into<String>,
+    ) -> &mut Self {
+        self.producers
+            .get_or_insert_with(Default::default)
+            .entry(field.into())
+            .or_default()
+            .push((name.into(), version.into()));
+        self
+    }
+
     pub fn start(&mut self, f: FunctionIndex) -> &mut Self {
+        match self.start {
+            Some(first) if first != f && self.start_conflict.is_none() => {
+                self.start_conflict = Some((first, f));
+            }
+            _ => (),
+        }
         self.start = Some(f);
         self
     }
 
+    #[track_caller]
     pub fn func(
         &mut self,
         name: impl AsRef<str>,
@@ -286,17 +866,47 @@ impl<'a> Module<'a> {
         results: impl IntoIterator<Item = ValType>,
         locals: impl Into<TypeList<Local>>,
     ) -> &mut Function<'a> {
-        let params = params.into().items.into_values().collect::<Vec<_>>();
+        self.check_last();
+        let params = params.into();
+        let mut local_names = params.names;
+        let params = params.items.into_values().collect::<Vec<_>>();
         let results = results.into_iter().collect::<Vec<_>>();
+        let mut locals = locals.into();
+        locals.relocate(params.len() as u32);
+        local_names.append(&mut locals.names);
+        assert!(
+            locals.items.is_empty() || locals.offset() as usize == params.len(),
+            "Local handles for `{}` start at {} but it has {} parameters, use `TypeList::with_offset` or `Function::local`",
+            name.as_ref(),
+            locals.offset(),
+            params.len(),
+        );
         let type_index = self
-            .types()
-            .push(|t| t.function(params.clone(), results.clone()));
-        self.funcs.function(type_index.into());
-        let index = self.imports.len() + self.funcs.len() - 1;
+            .func_type(params.iter().copied(), results.iter().copied())
+            .index();
+        for ty in locals.items.values() {
+            features::check_val_type(self.enabled_features(), ty);
+        }
+        self.funcs.function(type_index);
+        let index = self.func_space.define();
+        self.context.lock().funcs.push(type_index);
+        let locals = locals.items.into_values().collect::<Vec<_>>();
+        let stack = self.typed.then(|| {
+            let all_locals = params.iter().chain(&locals).copied().collect();
+            Stack::new(self.context.clone(), all_locals, results.clone())
+        });
         let f = Function {
-            body: Builder::default(),
+            body: Builder {
+                features: self.features,
+                context: Some(self.context.clone()),
+                stack,
+                ..Default::default()
+            },
             name: name.as_ref().to_string(),
-            locals: locals.into().items.into_values().collect(),
+            params,
+            results,
+            locals,
+            local_names,
             type_index: FunctionTypeIndex::from(type_index),
             index,
             export: None,
@@ -305,120 +915,735 @@ impl<'a> Module<'a> {
         self.defs.last_mut().unwrap()
     }
 
+    #[track_caller]
+    pub fn func_type(
+        &mut self,
+        params: impl IntoIterator<Item = ValType>,
+        results: impl IntoIterator<Item = ValType>,
+    ) -> FunctionTypeIndex {
+        let ty = wasm_encoder::FuncType::new(params, results);
+        FunctionTypeIndex::from(self.intern_type(InternedType::Func(ty)))
+    }
+
+    /// The block type for a block with `params` and `results`, adding a function type when
+    /// it's needed
+    #[track_caller]
+    pub fn block_type(
+        &mut self,
+        params: impl IntoIterator<Item = ValType>,
+        results: impl IntoIterator<Item = ValType>,
+    ) -> BlockType {
+        let params = params.into_iter().collect::<Vec<_>>();
+        let results = results.into_iter().collect::<Vec<_>>();
+        match (&params[..], &results[..]) {
+            ([], []) => BlockType::Empty,
+            ([], [ty]) => BlockType::Result(*ty),
+            _ => self.func_type(params, results).block_type(),
+        }
+    }
+
+    #[track_caller]
     pub fn struct_type(&mut self, def: impl Into<StructType>) -> StructTypeIndex {
-        StructTypeIndex::from(self.types().push(|t| t.struct_(def.into().fields.clone())))
+        StructTypeIndex::from(self.intern_type(InternedType::Struct(def.into().fields)))
     }
 
-    pub fn array_type(&mut self, def: &StructType) -> ArrayTypeIndex {
-        ArrayTypeIndex::from(self.types().push(|t| t.struct_(def.fields.clone())))
+    #[track_caller]
+    pub fn array_type(&mut self, def: impl Into<ArrayType>) -> ArrayTypeIndex {
+        let def = def.into();
+        let field = field_type(def.item, def.mutable);
+        ArrayTypeIndex::from(self.intern_type(InternedType::Array(field)))
     }
 
-    pub fn types<'b>(&'b mut self) -> Types<'b> {
-        Types(&mut self.types)
+    #[track_caller]
+    pub fn named_struct_type<S: Into<String>>(
+        &mut self,
+        name: impl Into<String>,
+        fields: impl IntoIterator<Item = (S, FieldType)>,
+    ) -> StructTypeIndex {
+        let (names, fields): (Vec<_>, Vec<_>) = fields.into_iter().unzip();
+        // Named types aren't interned, structurally equal types with different names stay
+        // separate so neither name is lost
+        let index = StructTypeIndex::from(self.define_type(InternedType::Struct(fields)));
+        self.name_type(index, name);
+        for (i, field) in names.into_iter().enumerate() {
+            self.name_field(index, i as u32, field);
+        }
+        index
     }
 
-    pub fn tables<'b>(&'b mut self) -> Tables<'b> {
-        Tables(&mut self.tables)
+    pub fn name_type(&mut self, ty: impl Into<u32>, name: impl Into<String>) -> &mut Self {
+        self.names.types.push((name.into(), ty.into()));
+        self
     }
 
-    pub fn active_element(
+    pub fn name_field(
         &mut self,
-        table_index: Option<u32>,
-        elements: Elements,
-    ) -> &mut ElementSection {
-        self.elements
-            .active(table_index, &ConstExpr::i32_const(0), elements)
+        ty: StructTypeIndex,
+        field: u32,
+        name: impl Into<String>,
+    ) -> &mut Self {
+        self.names
+            .fields
+            .entry(ty.index())
+            .or_default()
+            .push((name.into(), field));
+        self
     }
 
-    pub fn passive_element(&mut self, elements: Elements) -> &mut ElementSection {
-        self.elements.passive(elements)
+    pub fn name_memory(&mut self, memory: MemoryIndex, name: impl Into<String>) -> &mut Self {
+        self.names.memories.push((name.into(), memory.index()));
+        self
     }
 
-    pub fn element_segment(&mut self, seg: ElementSegment) -> &mut ElementSection {
-        self.elements.segment(seg)
+    pub fn name_table(&mut self, table: TableIndex, name: impl Into<String>) -> &mut Self {
+        self.names.tables.push((name.into(), table.index()));
+        self
     }
 
-    pub fn finish(mut self) -> Vec<u8> {
-        let mut module = wasm_encoder::Module::new();
-        for i in self.import_info {
-            self.func_names.append(i.1, &i.0);
-        }
+    pub fn name_tag(&mut self, tag: TagIndex, name: impl Into<String>) -> &mut Self {
+        self.names.tags.push((name.into(), tag.index()));
+        self
+    }
 
-        for def in self.defs {
-            let mut f = wasm_encoder::Function::new_with_locals_types(def.locals);
+    pub fn name_data(&mut self, data: DataSegmentIndex, name: impl Into<String>) -> &mut Self {
+        self.names.data.push((name.into(), data.index()));
+        self
+    }
 
-            for instr in &def.body.instrs {
-                f.instruction(instr);
-            }
-            f.instruction(&Instr::End);
-            self.code.function(&f);
-            self.func_names.append(def.index, &def.name);
+    pub fn name_element(
+        &mut self,
+        elem: ElementSegmentIndex,
+        name: impl Into<String>,
+    ) -> &mut Self {
+        self.names.elements.push((name.into(), elem.index()));
+        self
+    }
 
-            if let Some(name) = def.export {
-                self.exports
-                    .export(&name, wasm_encoder::ExportKind::Func, def.index);
-            }
+    #[track_caller]
+    fn intern_type(&mut self, ty: InternedType) -> u32 {
+        if let Some(index) = self.context.lock().interned.get(&ty) {
+            return *index;
         }
+        self.check_type(&ty);
+        let index = self.context.lock().intern(ty);
+        self.flush_types();
+        index
+    }
 
-        for g in self.global_defs {
-            if let Some(name) = g.export {
-                self.exports
-                    .export(&name, wasm_encoder::ExportKind::Global, g.index);
-            }
-        }
+    #[track_caller]
+    fn define_type(&mut self, ty: InternedType) -> u32 {
+        self.check_type(&ty);
+        let index = self.context.lock().define(ty);
+        self.flush_types();
+        index
+    }
 
-        module.section(&self.types);
-        module.section(&self.imports);
-        module.section(&self.funcs);
-        module.section(&self.tables);
-        module.section(&self.memory);
-        module.section(&self.globals);
-        module.section(&self.exports);
+    #[track_caller]
+    fn check_type(&self, ty: &InternedType) {
+        let features = self.enabled_features();
+        match ty {
+            InternedType::Func(f) => features::check_func_type(features, f.params(), f.results()),
+            InternedType::Struct(_) => features::check_gc_type(features, "Struct type"),
+            InternedType::Array(_) => features::check_gc_type(features, "Array type"),
+        }
+    }
 
-        if let Some(start) = self.start {
-            module.section(&wasm_encoder::StartSection {
-                function_index: start.0,
-            });
+    /// Encode the types added to the context since the last call, including the block types
+    /// interned by builders
+    fn flush_types(&mut self) {
+        for ty in std::mem::take(&mut self.context.lock().pending) {
+            let t = self.types.ty();
+            match ty {
+                InternedType::Func(f) => t.func_type(&f),
+                InternedType::Struct(fields) => t.struct_(fields),
+                InternedType::Array(field) => t.array(&field.element_type, field.mutable),
+            }
         }
+    }
 
-        module.section(&self.elements);
-        module.section(&self.code);
-        module.section(&self.data);
+    #[track_caller]
+    pub fn rec_group<R>(&mut self, f: impl FnOnce(&mut RecGroup) -> R) -> R {
+        features::check_gc_type(self.enabled_features(), "Rec group");
+        self.flush_types();
+        let mut context = self.context.lock();
+        let mut group = RecGroup::new(context.types.len() as u32);
+        let res = f(&mut group);
+        let types = group.finish();
+        context
+            .types
+            .extend(types.iter().map(|ty| match &ty.composite_type.inner {
+                wasm_encoder::CompositeInnerType::Func(f) => Some(f.clone()),
+                _ => None,
+            }));
+        self.types.ty().rec(types);
+        res
+    }
 
-        // Set names
-        self.names.functions(&self.func_names);
-        self.names.globals(&self.global_names);
-        module.section(&self.names);
+    pub fn types<'b>(&'b mut self) -> Types<'b> {
+        self.flush_types();
+        Types(&mut self.types, &self.context)
+    }
 
-        // Finish
-        module.finish()
+    pub fn tables<'b>(&'b mut self) -> Tables<'b> {
+        let features = self.enabled_features();
+        Tables(
+            &mut self.tables,
+            &mut self.table_space,
+            &mut self.entities.tables,
+            features,
+        )
     }
 
-    pub fn data_segment(&mut self, offset: &ConstExpr, data: impl AsRef<[u8]>) -> DataSegmentIndex {
-        self.data.active(0, offset, data.as_ref().to_vec());
-        DataSegmentIndex::from(self.data.len() - 1)
+    #[track_caller]
+    pub fn table(&mut self, ty: TableType) -> TableIndex {
+        self.tables().push(ty)
     }
 
-    pub fn memory(&mut self, mt: MemoryType) -> MemoryIndex {
-        self.memory.memory(mt);
-        MemoryIndex::from(self.memory.len() - 1)
+    pub fn active_element(
+        &mut self,
+        table: Option<TableIndex>,
+        offset: &ConstExpr,
+        elements: Elements,
+    ) -> ElementSegmentIndex {
+        self.element_segment(ElementSegment {
+            mode: ElementMode::Active {
+                table: table.map(|t| t.index()),
+                offset,
+            },
+            elements,
+        })
     }
 
-    pub fn save(self, path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
-        let bytes = self.finish();
-        std::fs::write(path, bytes)?;
-        Ok(())
+    pub fn passive_element(&mut self, elements: Elements) -> ElementSegmentIndex {
+        self.element_segment(ElementSegment {
+            mode: ElementMode::Passive,
+            elements,
+        })
     }
 
-    pub fn validate(self) -> anyhow::Result<Vec<u8>> {
+    pub fn declared_element(&mut self, elements: Elements) -> ElementSegmentIndex {
+        self.element_segment(ElementSegment {
+            mode: ElementMode::Declared,
+            elements,
+        })
+    }
+
+    pub fn element_segment(&mut self, seg: ElementSegment) -> ElementSegmentIndex {
+        if let Elements::Functions(funcs) = &seg.elements {
+            self.element_funcs.extend(funcs.iter().copied());
+        }
+        self.entities.elements.push(match &seg.elements {
+            Elements::Functions(_) => RefType::FUNCREF,
+            Elements::Expressions(ty, _) => *ty,
+        });
+        self.elements.segment(seg);
+        ElementSegmentIndex::from(self.elements.len() - 1)
+    }
+
+    /// Track the operand stack of functions added after this is enabled, pushing a
+    /// wrongly typed instruction causes a panic. See `Builder::stack`.
+    pub fn typed(&mut self, enable: bool) -> &mut Self {
+        self.typed = enable;
+        self
+    }
+
+    /// Validate each function as soon as it's complete, which is when the next function is
+    /// started or the module is finished. Validation errors cause a panic.
+    pub fn check_functions(&mut self, enable: bool) -> &mut Self {
+        self.check_functions = enable;
+        self
+    }
+
+    fn check_last(&mut self) {
+        if !self.check_functions {
+            return;
+        }
+        if let Some(f) = self.defs.last().map(|f| f.index()) {
+            if let Err(e) = self.check(f) {
+                panic!("{e}");
+            }
+        }
+    }
+
+    /// Validate a single function using the types, imports and other definitions that have
+    /// been added to the module so far. Only the body of `f` is validated, so the error offset
+    /// is relative to the start of the body.
+    pub fn check(&mut self, f: FunctionIndex) -> Result<(), Box<ValidationError>> {
+        let Ok(def) = self.defs.binary_search_by_key(&f.index(), |def| def.index) else {
+            // Imported functions have no body to check
+            return Ok(());
+        };
+        self.flush_types();
+        let features = self.enabled_features();
+        let context = self.context.lock();
+        let current = self
+            .checker
+            .as_ref()
+            .is_some_and(|c| c.is_current(features, &context, &self.entities));
+        if !current {
+            let checker = Checker::new(&self.types, &context, &self.entities, features)
+                .map_err(|e| Box::new(ValidationError::from_error(&e)))?;
+            self.checker = Some(std::sync::Arc::new(checker));
+        }
+        let checker = self.checker.as_ref().unwrap();
+        checker.validate(&self.defs[def], &context, self.data.len(), features)
+    }
+
+    /// Finish the module, returning an error instead of panicking or producing an invalid
+    /// module when blocks are unbalanced, export names are duplicated, handles are out of range
+    /// or more than one start function is set
+    pub fn try_finish(mut self) -> Result<Vec<u8>, Error> {
+        if let Some((first, second)) = self.start_conflict {
+            return Err(Error::ConflictingStart { first, second });
+        }
+        if let Some(start) = self.start {
+            self.check_index("function", start.index(), self.func_space.len(), None)?;
+        }
+
+        let mut names = std::collections::BTreeSet::new();
+        let exports = self
+            .defs
+            .iter()
+            .filter_map(|f| f.export.as_ref())
+            .chain(self.global_defs.iter().filter_map(|g| g.export.as_ref()))
+            .chain(self.exports.iter().map(|(name, _, _)| name));
+        for name in exports {
+            if !names.insert(name) {
+                return Err(Error::DuplicateExport(name.clone()));
+            }
+        }
+        for (_, kind, index) in &self.exports {
+            let (kind, len) = match kind {
+                ExportKind::Func => ("function", self.func_space.len()),
+                ExportKind::Global => ("global", self.global_space.len()),
+                ExportKind::Memory => ("memory", self.memory_space.len()),
+                ExportKind::Table => ("table", self.table_space.len()),
+                ExportKind::Tag => ("tag", self.tag_space.len()),
+            };
+            self.check_index(kind, *index, len, None)?;
+        }
+
+        for def in &self.defs {
+            self.check_body(def)?;
+        }
+        if self.check_functions {
+            if let Some(f) = self.defs.last().map(|f| f.index()) {
+                self.check(f).map_err(Error::Validation)?;
+            }
+        }
+        Ok(self.encode())
+    }
+
+    fn check_index(
+        &self,
+        kind: &'static str,
+        index: u32,
+        len: u32,
+        at: Option<(&Function, usize)>,
+    ) -> Result<(), Error> {
+        if index < len {
+            return Ok(());
+        }
+        Err(Error::InvalidIndex {
+            kind,
+            index,
+            function: at.map(|(f, _)| f.name.clone()),
+            instr: at.map(|(_, i)| i),
+        })
+    }
+
+    fn check_body(&self, def: &Function) -> Result<(), Error> {
+        let funcs = self.func_space.len();
+        let globals = self.global_space.len();
+        let memories = self.memory_space.len();
+        let tables = self.table_space.len();
+        let tags = self.tag_space.len();
+        let locals = (def.params.len() + def.locals.len()) as u32;
+        let elements = self.elements.len();
+        let data = self.data.len();
+        let context = self.context.lock();
+        let types = context.types.len() as u32;
+
+        // The position of each open block and of its last `else` or `catch` clause
+        let mut blocks: Vec<(usize, usize)> = vec![];
+        for (i, instr) in def.body.instrs.iter().enumerate() {
+            let at = Some((def, i));
+            // The function body is the outermost label, `try_table` catches are resolved
+            // outside of the `try_table` so they're checked before it's pushed
+            let labels = blocks.len() as u32 + 1;
+            let depths = match instr {
+                Instr::Br(d) | Instr::BrIf(d) | Instr::BrOnNull(d) | Instr::BrOnNonNull(d) => {
+                    vec![*d]
+                }
+                Instr::BrTable(targets, default) => {
+                    targets.iter().chain([default]).copied().collect()
+                }
+                Instr::BrOnCast { relative_depth, .. }
+                | Instr::BrOnCastFail { relative_depth, .. } => vec![*relative_depth],
+                Instr::TryTable(_, catches) => catches
+                    .iter()
+                    .map(|catch| match catch {
+                        Catch::One { label, .. }
+                        | Catch::OneRef { label, .. }
+                        | Catch::All { label }
+                        | Catch::AllRef { label } => *label,
+                    })
+                    .collect(),
+                _ => vec![],
+            };
+            for depth in depths {
+                self.check_index("label", depth, labels, at)?;
+            }
+
+            match instr {
+                Instr::Block(_)
+                | Instr::Loop(_)
+                | Instr::If(_)
+                | Instr::TryTable(..)
+                | Instr::Try(_) => blocks.push((i, i)),
+                Instr::Else | Instr::Catch(_) | Instr::CatchAll | Instr::Delegate(_) => {
+                    let clause = blocks.last().map(|(_, clause)| &def.body.instrs[*clause]);
+                    let valid = matches!(
+                        (instr, clause),
+                        (Instr::Else, Some(Instr::If(_)))
+                            | (
+                                Instr::Catch(_) | Instr::CatchAll,
+                                Some(Instr::Try(_) | Instr::Catch(_))
+                            )
+                            | (Instr::Delegate(_), Some(Instr::Try(_)))
+                    );
+                    if !valid {
+                        return Err(Error::UnbalancedBlock {
+                            function: def.name.clone(),
+                            instr: i,
+                        });
+                    }
+                    if let Instr::Delegate(_) = instr {
+                        blocks.pop();
+                    } else if let Some((_, clause)) = blocks.last_mut() {
+                        *clause = i;
+                    }
+                }
+                Instr::End if blocks.pop().is_none() => {
+                    return Err(Error::UnbalancedBlock {
+                        function: def.name.clone(),
+                        instr: i,
+                    })
+                }
+                Instr::LocalGet(l) | Instr::LocalSet(l) | Instr::LocalTee(l) => {
+                    self.check_index("local", *l, locals, at)?;
+                }
+                _ => {}
+            }
+
+            for (kind, index) in Indices::of(instr) {
+                let len = match kind {
+                    "function" => funcs,
+                    "global" => globals,
+                    "memory" => memories,
+                    "table" => tables,
+                    "tag" => tags,
+                    "type" => types,
+                    "element" => elements,
+                    "data" => data,
+                    _ => unreachable!("unknown index kind {kind}"),
+                };
+                self.check_index(kind, index, len, at)?;
+            }
+        }
+        for (pos, actual) in &def.body.calls {
+            let (ty, callee) = match def.body.instrs.get(*pos) {
+                Some(Instr::Call(f) | Instr::ReturnCall(f)) => (context.signature(*f), 0),
+                Some(
+                    Instr::CallIndirect { type_index, .. }
+                    | Instr::ReturnCallIndirect { type_index, .. },
+                ) => (context.func_type(*type_index), 1),
+                Some(Instr::CallRef(t) | Instr::ReturnCallRef(t)) => (context.func_type(*t), 1),
+                _ => continue,
+            };
+            let params = ty.map(|f| f.params().len());
+            if let Some(params) = params {
+                if params + callee != *actual {
+                    return Err(Error::ArgumentCount {
+                        function: def.name.clone(),
+                        instr: *pos,
+                        expected: params + callee,
+                        actual: *actual,
+                    });
+                }
+            }
+        }
+        if let Some((instr, _)) = blocks.pop() {
+            return Err(Error::MissingEnd {
+                function: def.name.clone(),
+                instr,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.check_last();
+        self.encode()
+    }
+
+    fn encode(mut self) -> Vec<u8> {
+        self.flush_types();
+        let mut module = wasm_encoder::Module::new();
+        let mut resolver = Resolver {
+            funcs: self.func_space.resolve(),
+            globals: self.global_space.resolve(),
+            memories: self.memory_space.resolve(),
+            tables: self.table_space.resolve(),
+            tags: self.tag_space.resolve(),
+        };
+
+        let mut func_names = self.import_info;
+        let mut local_names: Vec<(u32, Vec<_>)> = vec![];
+        let mut labels: Vec<(u32, Vec<_>)> = vec![];
+        let mut exports = Vec::new();
+        let mut data_count = false;
+        let mut ref_funcs = std::collections::BTreeSet::new();
+        for def in self.defs {
+            self.code.function(&def.encode_body());
+            for instr in &def.body.instrs {
+                data_count |= matches!(
+                    instr,
+                    Instr::MemoryInit { .. }
+                        | Instr::DataDrop(_)
+                        | Instr::ArrayNewData { .. }
+                        | Instr::ArrayInitData { .. }
+                );
+                if let Instr::RefFunc(index) = instr {
+                    ref_funcs.insert(*index);
+                }
+            }
+            func_names.push((def.name, def.index));
+            local_names.push((
+                def.index,
+                def.local_names.into_iter().map(|(i, n)| (n, i)).collect(),
+            ));
+            labels.push((def.index, label_names(&def.body)));
+
+            if let Some(name) = def.export {
+                exports.push((name, ExportKind::Func, def.index));
+            }
+        }
+
+        for g in self.global_defs {
+            if let Some(name) = g.export {
+                exports.push((name, ExportKind::Global, g.index));
+            }
+        }
+
+        exports.extend(self.exports);
+
+        // Functions used with `ref.func` must be declared in an element segment
+        for (_, kind, index) in &exports {
+            if *kind == ExportKind::Func {
+                ref_funcs.remove(index);
+            }
+        }
+        ref_funcs.retain(|index| !self.element_funcs.contains(index));
+        if !ref_funcs.is_empty() {
+            let funcs = ref_funcs.into_iter().collect::<Vec<_>>();
+            self.elements.declared(Elements::Functions(funcs.into()));
+        }
+        let mut export_section = wasm_encoder::ExportSection::new();
+        let mut export_names = std::collections::BTreeSet::new();
+        for (name, kind, index) in &exports {
+            if !export_names.insert(name.as_str()) {
+                panic!("Duplicate export name: {name}");
+            }
+            export_section.export(name, *kind, *index);
+        }
+
+        let custom = &self.custom_sections;
+        section(&mut module, custom, SectionId::Type, Some(&self.types));
+        section(&mut module, custom, SectionId::Import, Some(&self.imports));
+        section(&mut module, custom, SectionId::Function, Some(&self.funcs));
+        section(&mut module, custom, SectionId::Table, Some(&self.tables));
+        section(&mut module, custom, SectionId::Memory, Some(&self.memory));
+        let tags = (!self.tags.is_empty()).then_some(&self.tags);
+        section(&mut module, custom, SectionId::Tag, tags);
+        section(&mut module, custom, SectionId::Global, Some(&self.globals));
+        section(
+            &mut module,
+            custom,
+            SectionId::Export,
+            Some(&export_section),
+        );
+        let start = self.start.map(|start| wasm_encoder::StartSection {
+            function_index: start.0,
+        });
+        section(&mut module, custom, SectionId::Start, start.as_ref());
+        section(
+            &mut module,
+            custom,
+            SectionId::Element,
+            Some(&self.elements),
+        );
+        let data_count = data_count.then(|| wasm_encoder::DataCountSection {
+            count: self.data.len(),
+        });
+        section(
+            &mut module,
+            custom,
+            SectionId::DataCount,
+            data_count.as_ref(),
+        );
+        section(&mut module, custom, SectionId::Code, Some(&self.code));
+        section(&mut module, custom, SectionId::Data, Some(&self.data));
+
+        // Resolve handles to their final indices
+        if !resolver.is_identity() {
+            module = resolver
+                .reencode(module.as_slice())
+                .expect("unable to resolve module indices");
+        }
+
+        // Set names
+        let mut names = wasm_encoder::NameSection::new();
+        names.functions(&name_map(func_names, |i| resolver.function(i)));
+        local_names.retain(|(_, locals)| !locals.is_empty());
+        if !local_names.is_empty() {
+            names.locals(&indirect_name_map(local_names, |i| resolver.function(i)));
+        }
+        labels.retain(|(_, labels)| !labels.is_empty());
+        if !labels.is_empty() {
+            names.labels(&indirect_name_map(labels, |i| resolver.function(i)));
+        }
+        let types = name_map(self.names.types, |i| i);
+        if !types.is_empty() {
+            names.types(&types);
+        }
+        let tables = name_map(self.names.tables, |i| resolver.table(i));
+        if !tables.is_empty() {
+            names.tables(&tables);
+        }
+        let memories = name_map(self.names.memories, |i| resolver.memory(i));
+        if !memories.is_empty() {
+            names.memories(&memories);
+        }
+        names.globals(&name_map(self.global_names, |i| resolver.global(i)));
+        let elements = name_map(self.names.elements, |i| i);
+        if !elements.is_empty() {
+            names.elements(&elements);
+        }
+        let data = name_map(self.names.data, |i| i);
+        if !data.is_empty() {
+            names.data(&data);
+        }
+        if !self.names.fields.is_empty() {
+            let fields = self.names.fields.into_iter().collect();
+            names.fields(&indirect_name_map(fields, |i| i));
+        }
+        let tags = name_map(self.names.tags, |i| resolver.tag(i));
+        if !tags.is_empty() {
+            names.tags(&tags);
+        }
+        module.section(&names);
+
+        if let Some(mut producers) = self.producers {
+            let processed_by = producers.entry("processed-by".to_string()).or_default();
+            if !processed_by.iter().any(|(name, _)| name == "wagen") {
+                processed_by.push(("wagen".to_string(), env!("CARGO_PKG_VERSION").to_string()));
+            }
+            let mut section = wasm_encoder::ProducersSection::new();
+            for (field, values) in producers {
+                let mut f = wasm_encoder::ProducersField::new();
+                for (name, version) in values {
+                    f.value(&name, &version);
+                }
+                section.field(&field, &f);
+            }
+            module.section(&section);
+        }
+
+        if let Some(features) = self.features {
+            module.section(&features::target_features(features));
+        }
+
+        for (placement, custom) in &self.custom_sections {
+            if matches!(
+                placement,
+                Placement::End
+                    | Placement::Before(SectionId::Custom)
+                    | Placement::After(SectionId::Custom)
+            ) {
+                module.section(custom);
+            }
+        }
+
+        // Finish
+        module.finish()
+    }
+
+    pub fn data_segment(&mut self, offset: &ConstExpr, data: impl AsRef<[u8]>) -> DataSegmentIndex {
+        self.active_data(MemoryIndex::from(0), offset, data)
+    }
+
+    pub fn active_data(
+        &mut self,
+        memory: MemoryIndex,
+        offset: &ConstExpr,
+        data: impl AsRef<[u8]>,
+    ) -> DataSegmentIndex {
+        self.data
+            .active(memory.index(), offset, data.as_ref().to_vec());
+        DataSegmentIndex::from(self.data.len() - 1)
+    }
+
+    pub fn passive_data(&mut self, data: impl AsRef<[u8]>) -> DataSegmentIndex {
+        self.data.passive(data.as_ref().to_vec());
+        DataSegmentIndex::from(self.data.len() - 1)
+    }
+
+    #[track_caller]
+    pub fn memory(&mut self, mt: MemoryType) -> MemoryIndex {
+        features::check_memory_type(self.enabled_features(), &mt);
+        self.memory.memory(mt);
+        self.entities.memories.push(mt);
+        MemoryIndex::from(self.memory_space.define())
+    }
+
+    /// Encode the module along with a source map that maps code section offsets to the Rust
+    /// source locations that emitted each instruction. When `url` is set a `sourceMappingURL`
+    /// custom section pointing at the source map is added to the module.
+    pub fn finish_with_source_map(mut self, url: Option<&str>) -> (Vec<u8>, String) {
+        if let Some(url) = url {
+            let mut data = vec![];
+            wasm_encoder::Encode::encode(url, &mut data);
+            self.custom_section(Placement::End, "sourceMappingURL", data);
+        }
+        let locations = self
+            .defs
+            .iter()
+            .map(|f| f.body.locations.clone())
+            .collect::<Vec<_>>();
         let bytes = self.finish();
-        validate(&bytes)?;
+        let map = source_map::generate(&bytes, &locations).expect("invalid module");
+        (bytes, map)
+    }
+
+    pub fn save(self, path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
+        let bytes = self.finish();
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    pub fn validate(self) -> anyhow::Result<Vec<u8>> {
+        let defs = self.defs.clone();
+        let features = self.enabled_features();
+        let bytes = self.try_finish()?;
+        if let Err(e) = validate_with_features(&bytes, features) {
+            return Err(ValidationError::new(&e, &bytes, &defs).into());
+        }
         Ok(bytes)
     }
 
     pub fn validate_save(self, path: impl AsRef<std::path::Path>) -> anyhow::Result<()> {
-        let bytes = self.finish();
-        validate(&bytes)?;
+        let bytes = self.validate()?;
         std::fs::write(path, bytes)?;
         Ok(())
     }
@@ -427,8 +1652,14 @@ impl<'a> Module<'a> {
 pub fn validate(
     data: impl AsRef<[u8]>,
 ) -> Result<wasmparser::types::Types, wasmparser::BinaryReaderError> {
-    wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all())
-        .validate_all(data.as_ref())
+    validate_with_features(data, WasmFeatures::all())
+}
+
+pub fn validate_with_features(
+    data: impl AsRef<[u8]>,
+    features: WasmFeatures,
+) -> Result<wasmparser::types::Types, wasmparser::BinaryReaderError> {
+    wasmparser::Validator::new_with_features(features).validate_all(data.as_ref())
 }
 
 #[cfg(feature = "extism")]
@@ -465,6 +1696,23 @@ impl<'a> Module<'a> {
     }
 }
 
+fn section(
+    module: &mut wasm_encoder::Module,
+    custom: &[(Placement, CustomSection)],
+    id: SectionId,
+    section: Option<&impl wasm_encoder::Section>,
+) {
+    for (_, c) in custom.iter().filter(|c| c.0 == Placement::Before(id)) {
+        module.section(c);
+    }
+    if let Some(section) = section {
+        module.section(section);
+    }
+    for (_, c) in custom.iter().filter(|c| c.0 == Placement::After(id)) {
+        module.section(c);
+    }
+}
+
 pub fn any_type(nullable: bool) -> ValType {
     ValType::Ref(RefType {
         nullable,
@@ -571,4 +1819,1247 @@ mod tests {
         let module = Module::new();
         assert!(module.validate().is_ok());
     }
+
+    #[test]
+    fn import_entities() {
+        let mut module = Module::new();
+        let memory = module.import_memory(
+            "env",
+            "memory",
+            MemoryType {
+                page_size_log2: None,
+                minimum: 1,
+                maximum: None,
+                memory64: false,
+                shared: false,
+            },
+        );
+        let offset = module.import_global(
+            "env",
+            "offset",
+            None,
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: false,
+                shared: false,
+            },
+        );
+        module.import_tag("env", "error", [ValType::I32]);
+        let counter = module
+            .global(
+                "counter",
+                ValType::I32,
+                true,
+                false,
+                &ConstExpr::i32_const(0),
+            )
+            .index();
+        assert_eq!(memory.index(), 0);
+        assert_eq!(offset.index(), 0);
+        assert_eq!(counter.index(), 1);
+        module
+            .func("load", [], [ValType::I32], [])
+            .push(offset)
+            .push(Instr::I32Load(MemArg {
+                offset: 0,
+                align: 2,
+                memory_index: memory.index(),
+            }))
+            .push(counter)
+            .push(Instr::I32Add)
+            .export("load");
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn import_after_definition() {
+        let mut module = Module::new();
+        let counter = module
+            .global(
+                "counter",
+                ValType::I64,
+                true,
+                false,
+                &ConstExpr::i64_const(0),
+            )
+            .index();
+        let f = module
+            .func("f", [], [ValType::I64], [])
+            .push(counter)
+            .index();
+        let g = module.import("env", "g", None, [ValType::I32], [ValType::I32]);
+        let base = module.import_global(
+            "env",
+            "base",
+            None,
+            GlobalType {
+                val_type: ValType::I32,
+                mutable: false,
+                shared: false,
+            },
+        );
+        module
+            .func("h", [], [ValType::I64], [])
+            .push(base)
+            .push(g)
+            .push(Instr::Drop)
+            .push(f)
+            .export("h");
+        assert_eq!(f.index(), 0);
+        assert_eq!(g.index(), 1);
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn locals_after_params() {
+        let mut module = Module::new();
+        let f = module.func("sum", [ValType::I32, ValType::I32], [ValType::I32], []);
+        let (a, b) = (f.param(0), f.param(1));
+        let total = f.local(ValType::I32);
+        assert_eq!(total.index(), 2);
+        assert_eq!(f.local_type(total.index()), Some(ValType::I32));
+        f.push(a)
+            .push(b)
+            .push(Instr::I32Add)
+            .push(total.set())
+            .push(total)
+            .export("sum");
+
+        let mut params = TypeList::new();
+        let x = params.push(ValType::I64);
+        let mut locals = TypeList::<Local>::with_offset(1);
+        let y = locals.push(ValType::I64);
+        module
+            .func("double", params, [ValType::I64], locals)
+            .push(x)
+            .push(y.tee())
+            .push(y)
+            .push(Instr::I64Add);
+
+        module
+            .func("plain", [ValType::I32], [ValType::I64], [ValType::I64])
+            .push(Instr::LocalGet(1));
+        assert_eq!(module.defs[2].locals, [ValType::I64]);
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "Local handles for `f` start at 0 but it has 1 parameters")]
+    fn conflicting_local_handles() {
+        let mut module = Module::new();
+        let mut locals = TypeList::<Local>::new();
+        locals.push(ValType::I32);
+        module.func("f", [ValType::I32], [], locals);
+    }
+
+    #[test]
+    fn array_instructions() {
+        let mut module = Module::new();
+        let arr = module.array_type(ArrayType {
+            item: StorageType::I8,
+            mutable: true,
+        });
+        let f = module.func("copy", [], [ValType::I32], []);
+        let a = f.local(arr.val_type(false));
+        f.push(4i32)
+            .push(arr.array_new_default())
+            .push(a.set())
+            .push(a)
+            .push(0i32)
+            .push(a)
+            .push(1i32)
+            .push(3i32)
+            .push(arr.array_copy(arr))
+            .push(a)
+            .push(0i32)
+            .push(7i32)
+            .push(1i32)
+            .push(arr.array_fill())
+            .push([Instr::I32Const(1), Instr::I32Const(2)])
+            .push(arr.array_new_fixed(2))
+            .push(Instr::Drop)
+            .push(a)
+            .push(arr.array_len());
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn recursive_types() {
+        let mut module = Module::new();
+        let point = module.struct_type([field_type(StorageType::Val(ValType::I32), false)]);
+        let (node, shape, circle) = module.rec_group(|g| {
+            let node = g.reserve::<StructTypeHandle>();
+            g.define_struct(
+                node,
+                [
+                    field_type(StorageType::Val(ValType::I64), false),
+                    field_type(StorageType::Val(node.val_type(true)), true),
+                ],
+            );
+            let shape = g.struct_type([field_type(StorageType::Val(point.val_type(false)), false)]);
+            g.subtype(shape, None, false);
+            let circle = g.struct_type([
+                field_type(StorageType::Val(point.val_type(false)), false),
+                field_type(StorageType::Val(ValType::F64), false),
+            ]);
+            g.subtype(circle, Some(shape), true);
+            (node, shape, circle)
+        });
+        assert_eq!((node.index(), shape.index(), circle.index()), (1, 2, 3));
+        module
+            .func(
+                "push",
+                [ValType::I64, node.val_type(true)],
+                [node.val_type(false)],
+                [],
+            )
+            .push([Instr::LocalGet(0), Instr::LocalGet(1)])
+            .push(node.struct_new());
+        let f = module.func(
+            "upcast",
+            [circle.val_type(false)],
+            [shape.val_type(false)],
+            [],
+        );
+        let c = f.param(0);
+        f.push(c);
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn interned_types() {
+        let mut module = Module::new();
+        let log = module.import("env", "log", None, [ValType::I64], [ValType::I64]);
+        for i in 0..10 {
+            module
+                .func(format!("f{i}"), [ValType::I64], [ValType::I64], [])
+                .push(Instr::LocalGet(0))
+                .push(log);
+        }
+        let ty = module.func_type([ValType::I64], [ValType::I64]);
+        assert_eq!(ty.index(), 0);
+        assert_eq!(module.context.lock().types.len(), 1);
+
+        let field = field_type(StorageType::Val(ValType::I32), true);
+        assert_eq!(module.struct_type([field]), module.struct_type([field]));
+        assert_eq!(module.array_type(field), module.array_type(field));
+        assert_eq!(module.context.lock().types.len(), 3);
+
+        let a = module.named_struct_type("a", [("x", field)]);
+        let b = module.named_struct_type("b", [("y", field)]);
+        assert_eq!((a.index(), b.index()), (3, 4));
+        assert_eq!(module.struct_type([field]).index(), 1);
+        assert!(module.validate().is_ok());
+
+        // Types added directly are counted before the next interned type
+        let mut module = Module::new();
+        let raw = module.types().push(|ty| ty.function([], []));
+        assert_eq!(raw, 0);
+        assert_eq!(module.func_type([ValType::I32], []).index(), 1);
+        assert_eq!(module.func_type([], []).index(), 2);
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn export_entities() {
+        let mut module = Module::new();
+        let log = module
+            .import("env", "log", None, [ValType::I32], [])
+            .export(&mut module, "log");
+        let memory = module.memory(MemoryType {
+            page_size_log2: None,
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        memory.export(&mut module, "memory");
+        let ty = TableType {
+            element_type: RefType::FUNCREF,
+            table64: false,
+            minimum: 1,
+            maximum: None,
+            shared: false,
+        };
+        let first = module.tables().push(ty);
+        let table = module.table(ty);
+        assert_eq!((first.index(), table.index()), (0, 1));
+        module.export("table", ExportKind::Table, table);
+        module
+            .func("run", [], [], [])
+            .push(1i32)
+            .push(log)
+            .export("run");
+        let bytes = module.validate().unwrap();
+
+        let exports = wasmparser::Parser::new(0)
+            .parse_all(&bytes)
+            .find_map(|payload| match payload.unwrap() {
+                wasmparser::Payload::ExportSection(s) => Some(
+                    s.into_iter()
+                        .map(|e| e.unwrap().name.to_string())
+                        .collect::<Vec<_>>(),
+                ),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(exports, ["run", "log", "memory", "table"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Duplicate export name: run")]
+    fn duplicate_export() {
+        let mut module = Module::new();
+        module.func("a", [], [], []).export("run");
+        module.func("b", [], [], []).export("run");
+        module.finish();
+    }
+
+    #[test]
+    fn bulk_memory() {
+        let mut module = Module::new();
+        let mt = MemoryType {
+            page_size_log2: None,
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        };
+        let a = module.memory(mt);
+        let b = module.memory(mt);
+        module.active_data(b, &ConstExpr::i32_const(8), "active");
+        let hello = module.passive_data("hello");
+        module
+            .func("init", [], [], [])
+            .push([Instr::I32Const(0), Instr::I32Const(0), Instr::I32Const(5)])
+            .push(a.memory_init(hello))
+            .push(hello.data_drop())
+            .push([Instr::I32Const(0), Instr::I32Const(0), Instr::I32Const(5)])
+            .push(b.memory_copy(a))
+            .push([Instr::I32Const(16), Instr::I32Const(0), Instr::I32Const(4)])
+            .push(b.memory_fill());
+        let arr = module.array_type(field_type(StorageType::I8, false));
+        module
+            .func("array", [], [arr.val_type(false)], [])
+            .push([Instr::I32Const(0), Instr::I32Const(5)])
+            .push(arr.array_new_data(hello));
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn table_elements() {
+        let mut module = Module::new();
+        let table = module.table(TableType {
+            element_type: RefType::FUNCREF,
+            table64: false,
+            minimum: 4,
+            maximum: None,
+            shared: false,
+        });
+        let f = module.func("f", [], [ValType::I32], []).push(1i32).index();
+        let g = module.func("g", [], [ValType::I32], []).push(2i32).index();
+        module.active_element(
+            Some(table),
+            &ConstExpr::i32_const(2),
+            Elements::Functions(vec![f.index(), g.index()].into()),
+        );
+        let elems = module.passive_element(Elements::Functions(vec![g.index()].into()));
+        module.declared_element(Elements::Functions(vec![f.index()].into()));
+        module
+            .func("run", [], [ValType::I32], [])
+            .push([Instr::I32Const(0), Instr::I32Const(0), Instr::I32Const(1)])
+            .push(table.table_init(elems))
+            .push(elems.elem_drop())
+            .push([Instr::I32Const(1), Instr::I32Const(2), Instr::I32Const(1)])
+            .push(table.table_copy(table))
+            .push(f.ref_func())
+            .push(Instr::Drop)
+            .push(Instr::I32Const(3))
+            .push(table.table_get())
+            .push(Instr::Drop)
+            .push(table.table_size());
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn declare_ref_func() {
+        let mut module = Module::new();
+        let f = module.func("f", [], [ValType::I32], []).push(1i32).index();
+        let g = module.func("g", [], [], []).export("g").index();
+        module
+            .func("refs", [], [], [])
+            .push(f.ref_func())
+            .push(Instr::Drop)
+            .push(g.ref_func())
+            .push(Instr::Drop);
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn exceptions() {
+        let mut module = Module::new();
+        let imported = module.import_tag("env", "fatal", []);
+        let error = module.tag([ValType::I32]).export(&mut module, "error");
+        let f = module
+            .func("fail", [ValType::I32], [], [])
+            .push(Instr::LocalGet(0))
+            .throw(error)
+            .index();
+        module
+            .func("run", [ValType::I32], [ValType::I32], [])
+            .with_builder(|b| {
+                b.block(BlockType::Result(ValType::I32), |b: &mut Builder| {
+                    b.block(BlockType::Result(ValType::EXNREF), |b: &mut Builder| {
+                        b.try_table(
+                            BlockType::Empty,
+                            [error.catch(1), Catch::AllRef { label: 0 }],
+                            |b: &mut Builder| {
+                                b.push(Instr::LocalGet(0)).push(f).throw(imported);
+                            },
+                        )
+                        .push(Instr::Unreachable);
+                    })
+                    .throw_ref();
+                });
+            })
+            .export("run");
+
+        // The same function using labels, catch depths are relative to outside the try_table
+        module
+            .func("run_labels", [ValType::I32], [ValType::I32], [])
+            .with_builder(|b| {
+                b.labeled_block(BlockType::Result(ValType::I32), |b, done| {
+                    b.labeled_block(BlockType::Result(ValType::EXNREF), |b, caught| {
+                        let catches = [error.catch_label(b, done), b.catch_all_ref(caught)];
+                        let expected = [error.catch(1), Catch::AllRef { label: 0 }];
+                        assert_eq!(format!("{catches:?}"), format!("{expected:?}"));
+                        b.try_table(BlockType::Empty, catches, |b: &mut Builder| {
+                            b.push(Instr::LocalGet(0)).push(f).throw(imported);
+                        })
+                        .push(Instr::Unreachable);
+                    })
+                    .throw_ref();
+                });
+            });
+        assert!(module.validate().is_ok());
+    }
+
+    #[test]
+    fn custom_sections() {
+        let mut module = Module::new();
+        module
+            .custom_section(Placement::End, "manifest", b"{}".to_vec())
+            .custom_section(Placement::Before(SectionId::Type), "build", b"1".to_vec())
+            .custom_section(Placement::After(SectionId::Code), "after-code", vec![])
+            .producer("language", "lisp", "0.1");
+        let f = module.func("f", [], [], []).index();
+        module.import("env", "g", None, [], []);
+        module.start(f);
+        let bytes = module.validate().unwrap();
+
+        let mut sections = vec![];
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            match payload.unwrap() {
+                wasmparser::Payload::CustomSection(c) => {
+                    if c.name() == "producers" {
+                        let fields = wasmparser::ProducersSectionReader::new(
+                            wasmparser::BinaryReader::new(c.data(), 0),
+                        )
+                        .unwrap()
+                        .into_iter()
+                        .map(|f| f.unwrap().name.to_string())
+                        .collect::<Vec<_>>();
+                        assert_eq!(fields, ["language", "processed-by"]);
+                    }
+                    sections.push(c.name().to_string())
+                }
+                wasmparser::Payload::CodeSectionStart { .. } => sections.push("code".into()),
+                _ => (),
+            }
+        }
+        assert_eq!(
+            sections,
+            [
+                "build",
+                "code",
+                "after-code",
+                "name",
+                "producers",
+                "manifest"
+            ]
+        );
+    }
+
+    #[test]
+    fn name_section() {
+        let mut module = Module::new();
+        let point = module.named_struct_type(
+            "point",
+            [
+                ("x", field_type(StorageType::Val(ValType::I32), false)),
+                ("y", field_type(StorageType::Val(ValType::I32), false)),
+            ],
+        );
+        let memory = module.memory(MemoryType {
+            page_size_log2: None,
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        module.name_memory(memory, "heap");
+        let data = module.passive_data("abc");
+        module.name_data(data, "abc");
+        let mut params = TypeList::new();
+        params.push_named(ValType::I32, "n");
+        let f = module.func("count", params, [], []);
+        let i = f.local_named(ValType::I32, "i");
+        f.with_builder(|b| {
+            b.label("outer").block(BlockType::Empty, |b: &mut Builder| {
+                b.label("inner").loop_(BlockType::Empty, |b: &mut Builder| {
+                    b.local_incr(i, ValType::I32);
+                });
+            });
+        });
+        module.import("env", "log", None, [ValType::I32], []);
+        module.func("f", [point.val_type(true)], [], []);
+        let bytes = module.validate().unwrap();
+
+        let mut found = vec![];
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            let wasmparser::Payload::CustomSection(c) = payload.unwrap() else {
+                continue;
+            };
+            let wasmparser::KnownCustom::Name(reader) = c.as_known() else {
+                continue;
+            };
+            for name in reader {
+                let names = |map: wasmparser::NameMap| {
+                    map.into_iter()
+                        .map(|n| {
+                            let n = n.unwrap();
+                            format!("{}:{}", n.index, n.name)
+                        })
+                        .collect::<Vec<_>>()
+                        .join(",")
+                };
+                let indirect = |map: wasmparser::IndirectNameMap| {
+                    map.into_iter()
+                        .map(|n| {
+                            let n = n.unwrap();
+                            format!("{}[{}]", n.index, names(n.names))
+                        })
+                        .collect::<Vec<_>>()
+                        .join(",")
+                };
+                found.push(match name.unwrap() {
+                    wasmparser::Name::Function(m) => format!("functions {}", names(m)),
+                    wasmparser::Name::Local(m) => format!("locals {}", indirect(m)),
+                    wasmparser::Name::Label(m) => format!("labels {}", indirect(m)),
+                    wasmparser::Name::Type(m) => format!("types {}", names(m)),
+                    wasmparser::Name::Memory(m) => format!("memories {}", names(m)),
+                    wasmparser::Name::Data(m) => format!("data {}", names(m)),
+                    wasmparser::Name::Field(m) => format!("fields {}", indirect(m)),
+                    _ => continue,
+                });
+            }
+        }
+        assert_eq!(
+            found,
+            [
+                "functions 0:log,1:count,2:f",
+                "locals 1[0:n,1:i]",
+                "labels 1[0:outer,1:inner]",
+                "types 0:point",
+                "memories 0:heap",
+                "data 0:abc",
+                "fields 0[0:x,1:y]",
+            ]
+        );
+    }
+
+    #[test]
+    fn source_locations() {
+        let mut module = Module::new();
+        let f = module.func("f", [], [ValType::I32], []);
+        f.push(1i32);
+        let line = line!() + 1;
+        f.with_builder(|b| {
+            b.push(2i32).push(Instr::I32Add);
+        });
+        let body = &f.body;
+        assert_eq!(body.location(0).unwrap().line(), line - 2);
+        assert_eq!(body.location(1).unwrap().line(), line + 1);
+        assert_eq!(body.location(2).unwrap().line(), line + 1);
+        assert_eq!(body.location(0).unwrap().file(), file!());
+
+        let (bytes, map) = module.finish_with_source_map(Some("f.wasm.map"));
+        assert!(validate(&bytes).is_ok());
+        assert!(map.starts_with(r#"{"version":3,"sources":["src/lib.rs"],"names":[],"mappings":""#));
+        let mappings = map.rsplit_once(":\"").unwrap().1;
+        assert_eq!(mappings.split(',').count(), 3);
+    }
+
+    #[test]
+    fn validation_error() {
+        let mut module = Module::new();
+        module.func("ok", [], [], []);
+        module
+            .func("bad", [ValType::I64], [ValType::I32], [])
+            .push(1i32)
+            .push(Instr::LocalGet(0))
+            .push(Instr::I32Add)
+            .push(Instr::Drop)
+            .push(1i32);
+        let err = module.validate().unwrap_err();
+        let err = err.downcast_ref::<ValidationError>().unwrap();
+        assert_eq!(err.function.as_deref(), Some("bad"));
+        assert_eq!(err.function_index, Some(FunctionIndex::from(1)));
+        assert_eq!(err.instr, Some(2));
+        assert_eq!(err.expected.as_deref(), Some("i32"));
+        assert_eq!(err.actual.as_deref(), Some("i64"));
+        assert_eq!(err.location.unwrap().file(), file!());
+        assert_eq!(
+            err.context,
+            [
+                "  0: I32Const(1)",
+                "  1: LocalGet(0)",
+                "> 2: I32Add",
+                "  3: Drop",
+                "  4: I32Const(1)",
+                "  5: End"
+            ]
+        );
+    }
+
+    #[test]
+    fn check_function() {
+        let mut module = Module::new();
+        let f = module.func("f", [], [ValType::I32], []).push(1i64).index();
+        module.func("g", [], [ValType::I32], []).push(f);
+        let err = module.check(f).unwrap_err();
+        assert_eq!(err.function.as_deref(), Some("f"));
+        assert_eq!(err.instr, Some(1));
+        let g = FunctionIndex::from(1);
+        assert!(module.check(g).is_ok());
+
+        // Handles added after the first check are picked up, imports included
+        let imported = module.import("env", "i", None, [ValType::I32], [ValType::F64]);
+        let memory = module.memory(MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+            page_size_log2: None,
+        });
+        let global = module
+            .global("x", ValType::I32, true, false, &ConstExpr::i32_const(0))
+            .index();
+        let elements = module.passive_element(Elements::Functions(vec![g.index()].into()));
+        let h = module
+            .func("h", [], [ValType::F64], [])
+            .push([
+                Instr::I32Const(0),
+                Instr::I32Load(MemArg {
+                    offset: 0,
+                    align: 2,
+                    memory_index: memory.index(),
+                }),
+                Instr::GlobalSet(global.index()),
+                Instr::RefFunc(g.index()),
+                Instr::Drop,
+                Instr::ElemDrop(elements.index()),
+                Instr::GlobalGet(global.index()),
+                Instr::Call(imported.index()),
+            ])
+            .index();
+        module.check(h).unwrap();
+        module
+            .func("k", [], [], [])
+            .push(Instr::Call(imported.index()));
+        let err = module
+            .check(FunctionIndex::from(h.index() + 1))
+            .unwrap_err();
+        assert_eq!(err.function.as_deref(), Some("k"));
+        assert_eq!(err.instr, Some(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "in function `f` at instruction 1")]
+    fn check_functions_incrementally() {
+        let mut module = Module::new();
+        module.check_functions(true);
+        module.func("f", [], [], []).push(1i32);
+        module.func("g", [], [], []);
+    }
+
+    #[test]
+    fn feature_presets() {
+        let mut module = Module::mvp();
+        module
+            .func("f", [ValType::I32], [ValType::I32], [])
+            .push([Instr::LocalGet(0), Instr::I32Const(1), Instr::I32Add])
+            .export("f");
+        let bytes = module.validate().unwrap();
+        let section = wasmparser::Parser::new(0)
+            .parse_all(&bytes)
+            .find_map(|payload| match payload.unwrap() {
+                wasmparser::Payload::CustomSection(c) if c.name() == "target_features" => {
+                    Some(c.data().to_vec())
+                }
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(section, [0]);
+
+        let mut module = Module::wasm2();
+        module
+            .func("f", [ValType::I32], [ValType::I32], [])
+            .push([Instr::LocalGet(0), Instr::I32Extend8S]);
+        module.validate().unwrap();
+        assert!(validate_with_features(Module::new().finish(), WasmFeatures::WASM1).is_ok());
+    }
+
+    #[test]
+    #[should_panic(expected = "requires the `sign_extension` feature")]
+    fn feature_instruction() {
+        let mut module = Module::mvp();
+        module
+            .func("f", [ValType::I32], [ValType::I32], [])
+            .push([Instr::LocalGet(0), Instr::I32Extend8S]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Struct type requires the `gc` feature")]
+    fn feature_type() {
+        let mut module = Module::wasm2();
+        module.struct_type([]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Type `V128` requires the `simd` feature")]
+    fn feature_global() {
+        let mut module = Module::mvp();
+        module.global("x", ValType::V128, false, false, &ConstExpr::v128_const(0));
+    }
+
+    #[test]
+    #[should_panic(expected = "64-bit memory requires the `memory64` feature")]
+    fn feature_memory() {
+        let mut module = Module::mvp();
+        let mt = MemoryType {
+            minimum: 1,
+            maximum: None,
+            memory64: true,
+            shared: false,
+            page_size_log2: None,
+        };
+        module.import_memory("env", "memory", mt);
+    }
+
+    #[test]
+    #[should_panic(expected = "has to be called before anything is added")]
+    fn feature_late() {
+        let mut module = Module::new();
+        module.struct_type([]);
+        module.features(WasmFeatures::WASM2);
+    }
+
+    #[test]
+    fn try_finish_errors() {
+        let mut module = Module::new();
+        module
+            .func("f", [], [], [])
+            .push([Instr::Block(BlockType::Empty), Instr::Nop]);
+        assert_eq!(
+            module.try_finish(),
+            Err(Error::MissingEnd {
+                function: "f".into(),
+                instr: 0
+            })
+        );
+
+        let mut module = Module::new();
+        module.func("f", [], [], []).push([Instr::End]);
+        assert_eq!(
+            module.try_finish(),
+            Err(Error::UnbalancedBlock {
+                function: "f".into(),
+                instr: 0
+            })
+        );
+
+        // The last instruction of each is misplaced
+        let misplaced = [
+            vec![Instr::Block(BlockType::Empty), Instr::Else],
+            vec![Instr::Block(BlockType::Empty), Instr::CatchAll],
+            vec![Instr::Try(BlockType::Empty), Instr::Else],
+            vec![
+                Instr::Try(BlockType::Empty),
+                Instr::CatchAll,
+                Instr::Catch(0),
+            ],
+            vec![
+                Instr::I32Const(0),
+                Instr::If(BlockType::Empty),
+                Instr::Else,
+                Instr::Else,
+            ],
+        ];
+        for instrs in misplaced {
+            let last = instrs.len() - 1;
+            let mut module = Module::new();
+            module.tag([]);
+            module.func("f", [], [], []).push(instrs);
+            assert_eq!(
+                module.try_finish(),
+                Err(Error::UnbalancedBlock {
+                    function: "f".into(),
+                    instr: last
+                })
+            );
+        }
+
+        let mut module = Module::new();
+        module.func("f", [], [], []).push(GlobalIndex::from(3));
+        assert_eq!(
+            module.try_finish(),
+            Err(Error::InvalidIndex {
+                kind: "global",
+                index: 3,
+                function: Some("f".into()),
+                instr: Some(0)
+            })
+        );
+
+        let load = MemArg {
+            offset: 0,
+            align: 2,
+            memory_index: 7,
+        };
+        let catch = Catch::All { label: 2 };
+        let cases: [(Vec<Instr>, &str, u32, usize); 6] = [
+            (
+                vec![Instr::I32Const(0), Instr::I32Load(load)],
+                "memory",
+                7,
+                1,
+            ),
+            (
+                vec![Instr::StructGet {
+                    struct_type_index: 4,
+                    field_index: 0,
+                }],
+                "type",
+                4,
+                0,
+            ),
+            (vec![Instr::ArrayGet(3)], "type", 3, 0),
+            (
+                vec![Instr::Block(BlockType::Empty), Instr::Br(2), Instr::End],
+                "label",
+                2,
+                1,
+            ),
+            (
+                vec![
+                    Instr::TryTable(BlockType::Empty, vec![catch].into()),
+                    Instr::End,
+                ],
+                "label",
+                2,
+                0,
+            ),
+            (
+                vec![Instr::Block(BlockType::Empty), Instr::Throw(1), Instr::End],
+                "tag",
+                1,
+                1,
+            ),
+        ];
+        for (instrs, kind, index, instr) in cases {
+            let mut module = Module::new();
+            module.func("f", [], [], []).push(instrs);
+            assert_eq!(
+                module.try_finish(),
+                Err(Error::InvalidIndex {
+                    kind,
+                    index,
+                    function: Some("f".into()),
+                    instr: Some(instr)
+                })
+            );
+        }
+
+        let mut module = Module::new();
+        module.func("f", [], [], []).export("x");
+        module.func("g", [], [], []).export("x");
+        assert_eq!(module.try_finish(), Err(Error::DuplicateExport("x".into())));
+
+        let mut module = Module::new();
+        let f = module.func("f", [], [], []).index();
+        let g = module.func("g", [], [], []).index();
+        module.start(f).start(g);
+        assert_eq!(
+            module.try_finish(),
+            Err(Error::ConflictingStart {
+                first: f,
+                second: g
+            })
+        );
+
+        let mut module = Module::new();
+        let f = module.func("f", [], [], []);
+        let x = f.local(ValType::EXTERNREF);
+        let err = f
+            .builder()
+            .try_local_incr(x, ValType::EXTERNREF)
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidType { .. }));
+        assert!(f.body.instrs.is_empty());
+        assert!(module.try_finish().is_ok());
+
+        let mut module = Module::new();
+        module.check_functions(true);
+        module.func("f", [], [], []).push(1i32);
+        let Err(Error::Validation(err)) = module.try_finish() else {
+            panic!("expected a validation error");
+        };
+        assert_eq!((err.function.as_deref(), err.instr), (Some("f"), Some(1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid function index 9")]
+    fn unresolved_handle() {
+        let mut module = Module::new();
+        module.func("f", [], [], []).push(Instr::Call(9));
+        module.import("env", "g", None, [], []);
+        module.finish();
+    }
+
+    #[test]
+    fn typed_builder() {
+        let mut module = Module::new();
+        module.typed(true);
+        let g = module
+            .global("g", ValType::I64, true, false, &ConstExpr::i64_const(0))
+            .index();
+        let f = module.func("f", [ValType::I32], [ValType::I64], []);
+        let x = f.local(ValType::F64);
+        f.push([Instr::LocalGet(0), Instr::I32Const(1)]);
+        let stack = f.body.stack().unwrap();
+        assert_eq!(stack.operands, [Some(ValType::I32), Some(ValType::I32)]);
+        f.push(Instr::I32Add)
+            .push(Instr::If(BlockType::Result(ValType::I64)))
+            .push(g);
+        let stack = f.body.stack().unwrap();
+        assert_eq!(stack.frames.len(), 2);
+        assert_eq!(stack.frames[1].kind, FrameKind::If);
+        assert_eq!(stack.top(), Some(ValType::I64));
+        f.push([Instr::Else, Instr::I64Const(0), Instr::End]);
+        f.builder().incr(x).unwrap();
+        assert_eq!(f.body.stack().unwrap().operands, [Some(ValType::I64)]);
+        module.validate().unwrap();
+
+        let mut untyped = Builder::default();
+        assert_eq!(
+            untyped.incr(Local::from(0)).unwrap_err(),
+            Error::UnknownType { helper: "incr" }
+        );
+
+        // Functions and globals added later are known to the stacks of earlier functions, but
+        // not to the stacks of a cloned module
+        let mut module = Module::new();
+        module.typed(true);
+        module.func("f", [], [], []);
+        let h = module
+            .func("h", [], [ValType::F32], [])
+            .push(1.0f32)
+            .index();
+        module.defs[0].push(h);
+        assert_eq!(
+            module.defs[0].body.stack().unwrap().top(),
+            Some(ValType::F32)
+        );
+        let copy = module.clone();
+        let g = module
+            .global("g", ValType::I32, true, false, &ConstExpr::i32_const(0))
+            .index();
+        module.defs[0].push(Instr::Drop).push(g);
+        assert_eq!(
+            module.defs[0].body.stack().unwrap().top(),
+            Some(ValType::I32)
+        );
+        assert!(copy.context.lock().globals.is_empty());
+        module.defs[0].push(Instr::Drop);
+        module.validate().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "Type error at instruction 2 `I32Add`: expected I32, found I64")]
+    fn typed_builder_mismatch() {
+        let mut module = Module::new();
+        module.typed(true);
+        module
+            .func("f", [], [], [])
+            .push([Instr::I32Const(1), Instr::I64Const(1), Instr::I32Add]);
+    }
+
+    #[test]
+    fn labels() {
+        let mut module = Module::new();
+        let f = module.func("f", [ValType::I32], [], []);
+        let mut escaped = None;
+        f.with_builder(|b| {
+            b.labeled_block(BlockType::Empty, |b, outer| {
+                b.labeled_loop(BlockType::Empty, |b, inner| {
+                    b.push(Instr::LocalGet(0)).br_if(outer);
+                    b.labeled_if(BlockType::Empty, Instr::LocalGet(0), |b, _| {
+                        b.push(Instr::LocalGet(0)).br_table([outer, inner], outer);
+                    });
+                    b.br(inner);
+                    escaped = Some(inner);
+                });
+            });
+        });
+        let instrs = format!("{:?}", f.body.instrs);
+        assert!(instrs.contains("LocalGet(0), BrIf(1)"));
+        assert!(instrs.contains("BrTable([2, 1], 2)"));
+        assert!(instrs.contains("Br(0)"));
+        let escaped = escaped.unwrap();
+        assert_eq!(f.body.depth(escaped), Err(Error::LabelOutOfScope(escaped)));
+        module.validate().unwrap();
+
+        // Labels don't resolve in other builders, even when they have as many blocks open
+        let mut a = Builder::default();
+        a.labeled_block(BlockType::Empty, |a, label| {
+            let mut b = Builder::default();
+            b.push(Instr::Block(BlockType::Empty));
+            assert_eq!(b.depth(label), Err(Error::LabelOutOfScope(label)));
+            assert_eq!(a.depth(label), Ok(0));
+            let c = a.clone();
+            assert_eq!(c.depth(label), Err(Error::LabelOutOfScope(label)));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "is not in scope")]
+    fn label_out_of_scope() {
+        let mut b = Builder::default();
+        let mut label = None;
+        b.labeled_block(BlockType::Empty, |_, l| label = Some(l));
+        b.br(label.unwrap());
+    }
+
+    #[test]
+    fn multi_value_blocks() {
+        let mut module = Module::new();
+        assert!(matches!(
+            module.block_type([], [ValType::I32]),
+            BlockType::Result(ValType::I32)
+        ));
+        let swap = module.block_type([ValType::I32, ValType::I64], [ValType::I64, ValType::I32]);
+        let f = module.func("f", [ValType::I32], [ValType::I32, ValType::I64], []);
+        f.with_builder(|b| {
+            b.push((Instr::LocalGet(0), 1i64)).block(
+                ([ValType::I32, ValType::I64], [ValType::I64, ValType::I32]),
+                |b: &mut Builder| {
+                    b.push(Instr::Drop).push(Instr::Drop);
+                    b.labeled_if_else(
+                        ([], [ValType::I64, ValType::I32]),
+                        Instr::LocalGet(0),
+                        |b, _| {
+                            b.push((2i64, 3i32));
+                        },
+                        |b, _| {
+                            b.push((4i64, 5i32));
+                        },
+                    );
+                },
+            );
+            b.push([Instr::Drop, Instr::Drop]);
+        });
+        f.return_values((Instr::LocalGet(0), 4i64));
+        assert_eq!(
+            format!("{:?}", f.body.instrs[2]),
+            format!("Block({swap:?})")
+        );
+
+        // The instructions can be copied as they are, there's nothing left to resolve
+        let instrs = f.body.instrs.clone();
+        module
+            .func("g", [ValType::I32], [ValType::I32, ValType::I64], [])
+            .push(instrs);
+        let bytes = module.validate().unwrap();
+
+        let mut types = vec![];
+        for payload in wasmparser::Parser::new(0).parse_all(&bytes) {
+            if let wasmparser::Payload::TypeSection(reader) = payload.unwrap() {
+                for ty in reader.into_iter_err_on_gc_types() {
+                    types.push(ty.unwrap());
+                }
+            }
+        }
+        let BlockType::FunctionType(swap) = swap else {
+            panic!("expected a function type");
+        };
+        assert_eq!(
+            types[swap as usize].results(),
+            [wasmparser::ValType::I64, wasmparser::ValType::I32]
+        );
+        assert_eq!(types.len(), 3);
+    }
+
+    #[test]
+    fn switch() {
+        let mut module = Module::new();
+        let f = module.func("f", [ValType::I32], [ValType::I32], []);
+        let out = f.local(ValType::I32);
+        f.with_builder(|b| {
+            b.switch(
+                Instr::LocalGet(0),
+                [
+                    Case::new(|b, _| {
+                        b.push(10i32).push(out.set());
+                    }),
+                    Case::fall_through(|b, _| {
+                        b.push(20i32).push(out.set());
+                    }),
+                    Case::new(|b, exit| {
+                        b.push(out).push(Instr::I32Eqz).br_if(exit);
+                        b.local_incr(out, ValType::I32);
+                    }),
+                ],
+                |b, _| {
+                    b.push(-1i32).push(out.set());
+                },
+            );
+            b.push(out);
+        });
+        let instrs = format!("{:?}", f.body.instrs);
+        assert!(instrs.starts_with(
+            "[Block(Empty), Block(Empty), Block(Empty), Block(Empty), Block(Empty), \
+             LocalGet(0), BrTable([0, 1, 2], 3), End, I32Const(10), LocalSet(1), Br(3), End, \
+             I32Const(20), LocalSet(1), End, LocalGet(1), I32Eqz, BrIf(1)"
+        ));
+        module.validate().unwrap();
+    }
+
+    #[test]
+    fn typed_exprs() {
+        let mut module = Module::new();
+        let g = module
+            .global("g", ValType::F64, true, false, &ConstExpr::f64_const(1.5))
+            .index();
+        let mut params = TypeList::new();
+        let x = params.push(ValType::I32);
+        let y = params.push(ValType::I64);
+        let f = module.func("f", params, [ValType::I32], []);
+        let z = f.local(ValType::I32);
+        let expr = ((x.i32() + 1) * 2) << x.i32();
+        assert_eq!(
+            format!("{:?}", expr.instrs()),
+            "[LocalGet(0), I32Const(1), I32Add, I32Const(2), I32Mul, LocalGet(0), I32Shl]"
+        );
+        f.push(expr.set(z));
+        f.push((-g.f64() / 2.0).set_global(g));
+        f.push(
+            (y.i64().lt_s(10) & (1 - z.i32()).eqz())
+                | (g.f64().trunc_i64_s() ^ y.i64()).wrap().rem_u(3),
+        );
+        module.validate().unwrap();
+    }
+
+    #[test]
+    fn call_helpers() {
+        let mut module = Module::new();
+        let table = module.table(TableType {
+            element_type: RefType::FUNCREF,
+            table64: false,
+            minimum: 1,
+            maximum: None,
+            shared: false,
+        });
+        let add = module
+            .func("add", [ValType::I32, ValType::I32], [ValType::I32], [])
+            .with_builder(|b| {
+                b.push((Param::from(0), Param::from(1), Instr::I32Add));
+            })
+            .index();
+        let ty = module.func_type([ValType::I32, ValType::I32], [ValType::I32]);
+        module
+            .func("main", [], [ValType::I32], [])
+            .push(add.call((1, 2)))
+            .push(Instr::Drop)
+            .with_builder(|b| {
+                b.call_indirect(table, ty, [3, 4, 0])
+                    .push(Instr::Drop)
+                    .call_ref(ty, (5, 6, add.ref_func()))
+                    .push(Instr::Drop)
+                    .return_call(add, [7, 8]);
+            });
+        module.validate().unwrap();
+
+        let mut module = Module::new();
+        let add = module
+            .func("add", [ValType::I32, ValType::I32], [], [])
+            .index();
+        module.func("main", [], [], []).push(add.call([1]));
+        assert_eq!(
+            module.try_finish(),
+            Err(Error::ArgumentCount {
+                function: "main".to_string(),
+                instr: 1,
+                expected: 2,
+                actual: 1,
+            })
+        );
+
+        let mut module = Module::new();
+        let ty = module.rec_group(|group| group.func_type([ValType::I32], []));
+        module.func("main", [], [], []).with_builder(|b| {
+            b.call_ref(ty, [Instr::RefNull(HeapType::Concrete(ty.index()))]);
+        });
+        assert_eq!(
+            module.try_finish(),
+            Err(Error::ArgumentCount {
+                function: "main".to_string(),
+                instr: 1,
+                expected: 2,
+                actual: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn memory_access() {
+        let mut module = Module::new();
+        let mem32 = module.memory(MemoryType {
+            page_size_log2: None,
+            minimum: 1,
+            maximum: None,
+            memory64: false,
+            shared: false,
+        });
+        let mem64 = module.memory(MemoryType {
+            page_size_log2: None,
+            minimum: 1,
+            maximum: None,
+            memory64: true,
+            shared: false,
+        });
+        let load = mem64.load_i64_u16(16i64, 8);
+        let mut builder = Builder::default();
+        builder.push(load);
+        assert_eq!(
+            format!("{:?}", builder.instrs),
+            "[I64Const(16), I64Load16U(MemArg { offset: 8, align: 1, memory_index: 1 })]"
+        );
+        module
+            .func("copy", [], [], [])
+            .push(mem32.store_u8(0, mem64.load_u8(1i64, 0), 4))
+            .push(mem64.store_f64(8i64, mem32.load_f64(16, 0), 0))
+            .push(mem32.store_i64_u32(0, mem64.load_i64(0i64, 1 << 33), 0));
+        module.validate().unwrap();
+    }
 }
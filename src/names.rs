@@ -0,0 +1,66 @@
+use crate::*;
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Names {
+    pub types: Vec<(String, u32)>,
+    pub fields: std::collections::BTreeMap<u32, Vec<(String, u32)>>,
+    pub tables: Vec<(String, u32)>,
+    pub memories: Vec<(String, u32)>,
+    pub tags: Vec<(String, u32)>,
+    pub elements: Vec<(String, u32)>,
+    pub data: Vec<(String, u32)>,
+}
+
+pub(crate) fn name_map(
+    names: Vec<(String, u32)>,
+    resolve: impl Fn(u32) -> u32,
+) -> wasm_encoder::NameMap {
+    let mut names = names
+        .into_iter()
+        .map(|(name, index)| (resolve(index), name))
+        .collect::<Vec<_>>();
+    names.sort_by_key(|(index, _)| *index);
+    names.dedup_by_key(|(index, _)| *index);
+    let mut map = wasm_encoder::NameMap::new();
+    for (index, name) in names {
+        map.append(index, &name);
+    }
+    map
+}
+
+pub(crate) fn indirect_name_map(
+    names: Vec<(u32, Vec<(String, u32)>)>,
+    resolve: impl Fn(u32) -> u32,
+) -> wasm_encoder::IndirectNameMap {
+    let mut names = names
+        .into_iter()
+        .map(|(index, names)| (resolve(index), names))
+        .collect::<Vec<_>>();
+    names.sort_by_key(|(index, _)| *index);
+    let mut map = wasm_encoder::IndirectNameMap::new();
+    for (index, names) in names {
+        map.append(index, &name_map(names, |i| i));
+    }
+    map
+}
+
+/// Converts label names, which are keyed by instruction position, to label indices
+pub(crate) fn label_names(builder: &Builder) -> Vec<(String, u32)> {
+    let mut label = 0;
+    let mut names = builder.label_names.iter().peekable();
+    let mut out = vec![];
+    for (pos, instr) in builder.instrs.iter().enumerate() {
+        if !matches!(
+            instr,
+            Instr::Block(_) | Instr::Loop(_) | Instr::If(_) | Instr::TryTable(..) | Instr::Try(_)
+        ) {
+            continue;
+        }
+
+        while let Some((_, name)) = names.next_if(|(p, _)| **p <= pos) {
+            out.push((name.clone(), label));
+        }
+        label += 1;
+    }
+    out
+}
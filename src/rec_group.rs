@@ -0,0 +1,135 @@
+use crate::*;
+
+use wasm_encoder::{CompositeInnerType, CompositeType, SubType};
+
+/// A `rec` group of mutually recursive types, see `Module::rec_group`
+#[derive(Debug, Clone)]
+pub struct RecGroup {
+    base: u32,
+    types: Vec<Option<SubType>>,
+}
+
+impl RecGroup {
+    pub(crate) fn new(base: u32) -> Self {
+        RecGroup {
+            base,
+            types: Vec::new(),
+        }
+    }
+
+    /// Allocate an index for a type that will be defined later in the group,
+    /// this allows types to reference each other
+    pub fn reserve<T>(&mut self) -> Index<T> {
+        self.types.push(None);
+        Index::from(self.base + self.types.len() as u32 - 1)
+    }
+
+    pub fn struct_type(&mut self, def: impl Into<StructType>) -> StructTypeIndex {
+        let index = self.reserve();
+        self.define_struct(index, def);
+        index
+    }
+
+    pub fn array_type(&mut self, def: impl Into<ArrayType>) -> ArrayTypeIndex {
+        let index = self.reserve();
+        self.define_array(index, def);
+        index
+    }
+
+    pub fn func_type(
+        &mut self,
+        params: impl IntoIterator<Item = ValType>,
+        results: impl IntoIterator<Item = ValType>,
+    ) -> FunctionTypeIndex {
+        let index = self.reserve();
+        self.define_func(index, params, results);
+        index
+    }
+
+    pub fn define_struct(
+        &mut self,
+        index: StructTypeIndex,
+        def: impl Into<StructType>,
+    ) -> &mut Self {
+        let fields = def.into().fields.into_boxed_slice();
+        self.define(
+            index.index(),
+            CompositeInnerType::Struct(wasm_encoder::StructType { fields }),
+        )
+    }
+
+    pub fn define_array(&mut self, index: ArrayTypeIndex, def: impl Into<ArrayType>) -> &mut Self {
+        let def = def.into();
+        self.define(
+            index.index(),
+            CompositeInnerType::Array(wasm_encoder::ArrayType(field_type(def.item, def.mutable))),
+        )
+    }
+
+    pub fn define_func(
+        &mut self,
+        index: FunctionTypeIndex,
+        params: impl IntoIterator<Item = ValType>,
+        results: impl IntoIterator<Item = ValType>,
+    ) -> &mut Self {
+        self.define(
+            index.index(),
+            CompositeInnerType::Func(wasm_encoder::FuncType::new(params, results)),
+        )
+    }
+
+    /// Declare `index` as `sub` (or `sub final` when `is_final` is set) with an optional
+    /// supertype of the same kind
+    pub fn subtype<T>(
+        &mut self,
+        index: Index<T>,
+        supertype: Option<Index<T>>,
+        is_final: bool,
+    ) -> &mut Self {
+        let ty = self.get_mut(index.index());
+        ty.supertype_idx = supertype.map(|x| x.index());
+        ty.is_final = is_final;
+        self
+    }
+
+    fn get_mut(&mut self, index: u32) -> &mut SubType {
+        index
+            .checked_sub(self.base)
+            .and_then(|i| self.types.get_mut(i as usize))
+            .and_then(|x| x.as_mut())
+            .unwrap_or_else(|| panic!("Type {index} is not defined in this rec group"))
+    }
+
+    fn define(&mut self, index: u32, inner: CompositeInnerType) -> &mut Self {
+        let slot = index
+            .checked_sub(self.base)
+            .and_then(|i| self.types.get_mut(i as usize))
+            .unwrap_or_else(|| panic!("Type {index} is not reserved in this rec group"));
+        assert!(slot.is_none(), "Type {index} is already defined");
+        *slot = Some(SubType {
+            is_final: true,
+            supertype_idx: None,
+            composite_type: CompositeType {
+                inner,
+                shared: false,
+            },
+        });
+        self
+    }
+
+    pub(crate) fn finish(self) -> Vec<SubType> {
+        let base = self.base;
+        self.types
+            .into_iter()
+            .enumerate()
+            .map(|(i, ty)| {
+                ty.unwrap_or_else(|| {
+                    panic!(
+                        "Type {} was reserved in a rec group but never defined",
+                        base + i as u32
+                    )
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,103 @@
+use std::panic::Location;
+
+pub(crate) type Locations = Vec<(usize, &'static Location<'static>)>;
+
+/// Generate a version 3 source map that maps code section offsets in `wasm` back to the
+/// Rust source that emitted each instruction, `locations` contains an entry for each
+/// defined function in the same order as the code section
+pub(crate) fn generate(
+    wasm: &[u8],
+    locations: &[Locations],
+) -> Result<String, wasmparser::BinaryReaderError> {
+    let mut sources: Vec<&str> = vec![];
+    let mut mappings = String::new();
+    let mut prev = [0i64; 4];
+    let mut func = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        let wasmparser::Payload::CodeSectionEntry(body) = payload? else {
+            continue;
+        };
+        let locations = &locations[func];
+        func += 1;
+
+        let mut reader = body.get_operators_reader()?;
+        let mut index = 0;
+        let mut current = None;
+        while !reader.eof() {
+            let (_, offset) = reader.read_with_offset()?;
+            let n = locations.partition_point(|(pos, _)| *pos <= index);
+            index += 1;
+            let Some(loc) = n.checked_sub(1).map(|n| locations[n].1) else {
+                continue;
+            };
+            if current == Some(loc) {
+                continue;
+            }
+            current = Some(loc);
+
+            let source = match sources.iter().position(|s| *s == loc.file()) {
+                Some(i) => i,
+                None => {
+                    sources.push(loc.file());
+                    sources.len() - 1
+                }
+            };
+            let segment = [
+                offset as i64,
+                source as i64,
+                loc.line() as i64 - 1,
+                loc.column() as i64 - 1,
+            ];
+            if !mappings.is_empty() {
+                mappings.push(',');
+            }
+            for (value, prev) in segment.iter().zip(prev.iter_mut()) {
+                vlq(&mut mappings, value - *prev);
+                *prev = *value;
+            }
+        }
+    }
+
+    let sources = sources
+        .iter()
+        .map(|s| json_string(s))
+        .collect::<Vec<_>>()
+        .join(",");
+    Ok(format!(
+        r#"{{"version":3,"sources":[{sources}],"names":[],"mappings":"{mappings}"}}"#
+    ))
+}
+
+fn vlq(out: &mut String, value: i64) {
+    const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut value = if value < 0 {
+        ((-value) << 1) | 1
+    } else {
+        value << 1
+    };
+    loop {
+        let mut digit = value & 0x1f;
+        value >>= 5;
+        if value > 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit as usize] as char);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
@@ -0,0 +1,443 @@
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Function,
+    Block,
+    Loop,
+    If,
+    Else,
+    TryTable,
+    Try,
+}
+
+/// A control frame, `height` is the size of the operand stack when the frame was entered
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub params: Vec<ValType>,
+    pub results: Vec<ValType>,
+    pub height: usize,
+    /// Set after an unconditional branch, or when the stack can't be tracked because an
+    /// instruction's type is unknown. Missing operands are treated as any type until the frame
+    /// ends.
+    pub unreachable: bool,
+    unknown: bool,
+}
+
+/// The operand stack and control frames of a typed `Builder`, operands with an unknown type
+/// are `None`
+#[derive(Debug, Clone, Default)]
+pub struct Stack {
+    pub operands: Vec<Option<ValType>>,
+    pub frames: Vec<Frame>,
+    locals: Vec<ValType>,
+    context: SharedContext,
+}
+
+type Signature = (Vec<Option<ValType>>, Vec<Option<ValType>>);
+
+macro_rules! ty {
+    (i32) => {
+        Some(ValType::I32)
+    };
+    (i64) => {
+        Some(ValType::I64)
+    };
+    (f32) => {
+        Some(ValType::F32)
+    };
+    (f64) => {
+        Some(ValType::F64)
+    };
+    (v128) => {
+        Some(ValType::V128)
+    };
+    (v128f) => {
+        Some(ValType::V128)
+    };
+}
+
+macro_rules! signature {
+    (push $t:ident) => {
+        Some((vec![], vec![ty!($t)]))
+    };
+    (unary $t:ident) => {
+        Some((vec![ty!($t)], vec![ty!($t)]))
+    };
+    (binary $t:ident) => {
+        Some((vec![ty!($t), ty!($t)], vec![ty!($t)]))
+    };
+    (ternary $t:ident) => {
+        Some((vec![ty!($t), ty!($t), ty!($t)], vec![ty!($t)]))
+    };
+    (cmp $t:ident) => {
+        Some((vec![ty!($t), ty!($t)], vec![ty!(i32)]))
+    };
+    (test $t:ident) => {
+        Some((vec![ty!($t)], vec![ty!(i32)]))
+    };
+    (shift $t:ident) => {
+        Some((vec![ty!($t), ty!(i32)], vec![ty!($t)]))
+    };
+    (conversion $to:ident $from:ident) => {
+        Some((vec![ty!($from)], vec![ty!($to)]))
+    };
+    (splat $t:ident) => {
+        Some((vec![ty!($t)], vec![ty!(v128)]))
+    };
+    (extract $t:ident $lanes:literal) => {
+        Some((vec![ty!(v128)], vec![ty!($t)]))
+    };
+    (replace $t:ident $lanes:literal) => {
+        Some((vec![ty!(v128), ty!($t)], vec![ty!(v128)]))
+    };
+    // The address type depends on the memory, so it isn't checked
+    (load $t:ident) => {
+        Some((vec![None], vec![ty!($t)]))
+    };
+    (store $t:ident) => {
+        Some((vec![None, ty!($t)], vec![]))
+    };
+    (arity $params:literal -> $results:literal) => {
+        Some((vec![None; $params], vec![None; $results]))
+    };
+    ($($other:tt)*) => {
+        None
+    };
+}
+
+macro_rules! define_signature {
+    ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident ($($ann:tt)*) )*) => {
+        /// The operand types of instructions that don't depend on the module or the
+        /// control frames
+        fn signature(op: &wasmparser::Operator) -> Option<Signature> {
+            #[allow(unreachable_patterns)]
+            match op {
+                $(wasmparser::Operator::$op { .. } => signature!($($ann)*),)*
+                _ => None,
+            }
+        }
+    };
+}
+
+wasmparser::for_each_operator!(define_signature);
+
+fn compatible(expected: &ValType, actual: &ValType) -> bool {
+    // Subtyping isn't tracked, so any two reference types are compatible
+    expected == actual || matches!((expected, actual), (ValType::Ref(_), ValType::Ref(_)))
+}
+
+impl Stack {
+    pub(crate) fn new(
+        context: SharedContext,
+        locals: Vec<ValType>,
+        results: Vec<ValType>,
+    ) -> Stack {
+        Stack {
+            operands: vec![],
+            frames: vec![Frame {
+                kind: FrameKind::Function,
+                params: vec![],
+                results,
+                height: 0,
+                unreachable: false,
+                unknown: false,
+            }],
+            locals,
+            context,
+        }
+    }
+
+    /// The type of a parameter or local
+    pub fn local(&self, index: u32) -> Option<ValType> {
+        self.locals.get(index as usize).copied()
+    }
+
+    pub(crate) fn add_local(&mut self, ty: ValType) {
+        self.locals.push(ty);
+    }
+
+    /// The type of the value on top of the stack
+    pub fn top(&self) -> Option<ValType> {
+        self.operands.last().copied().flatten()
+    }
+
+    fn frame(&self) -> &Frame {
+        self.frames.last().expect("no control frame")
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().expect("no control frame");
+        self.operands.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn push(&mut self, ty: Option<ValType>) {
+        self.operands.push(ty);
+    }
+
+    fn pop(&mut self, expected: Option<ValType>) -> Result<Option<ValType>, String> {
+        let frame = self.frame();
+        if self.operands.len() == frame.height {
+            if frame.unreachable {
+                return Ok(expected);
+            }
+            return Err(match expected {
+                Some(ty) => format!("expected {ty:?} but the stack is empty"),
+                None => "expected a value but the stack is empty".to_string(),
+            });
+        }
+
+        let actual = self.operands.pop().unwrap();
+        match (&expected, &actual) {
+            (Some(e), Some(a)) if !compatible(e, a) => Err(format!("expected {e:?}, found {a:?}")),
+            (_, None) => Ok(expected),
+            _ => Ok(actual),
+        }
+    }
+
+    fn pop_all(&mut self, types: &[ValType]) -> Result<(), String> {
+        for ty in types.iter().rev() {
+            self.pop(Some(*ty))?;
+        }
+        Ok(())
+    }
+
+    fn push_all(&mut self, types: &[ValType]) {
+        self.operands.extend(types.iter().map(|ty| Some(*ty)));
+    }
+
+    fn block_type(&self, bt: &BlockType) -> Option<(Vec<ValType>, Vec<ValType>)> {
+        match bt {
+            BlockType::Empty => Some((vec![], vec![])),
+            BlockType::Result(ty) => Some((vec![], vec![*ty])),
+            BlockType::FunctionType(index) => self
+                .context
+                .lock()
+                .func_type(*index)
+                .map(|ty| (ty.params().to_vec(), ty.results().to_vec())),
+        }
+    }
+
+    fn push_frame(&mut self, kind: FrameKind, bt: &BlockType) -> Result<(), String> {
+        let Some((params, results)) = self.block_type(bt) else {
+            self.set_unreachable();
+            let height = self.operands.len();
+            self.frames.push(Frame {
+                kind,
+                params: vec![],
+                results: vec![],
+                height,
+                unreachable: true,
+                unknown: true,
+            });
+            return Ok(());
+        };
+        self.pop_all(&params)?;
+        let height = self.operands.len();
+        self.push_all(&params);
+        self.frames.push(Frame {
+            kind,
+            params,
+            results,
+            height,
+            unreachable: false,
+            unknown: false,
+        });
+        Ok(())
+    }
+
+    fn pop_frame(&mut self) -> Result<Frame, String> {
+        if self.frames.len() < 2 {
+            return Err("`end` without a matching block".to_string());
+        }
+        let frame = self.frame();
+        if frame.unknown {
+            let height = frame.height;
+            self.operands.truncate(height);
+        } else {
+            let results = frame.results.clone();
+            self.pop_all(&results)?;
+        }
+        let frame = self.frames.pop().unwrap();
+        if self.operands.len() != frame.height {
+            return Err(format!(
+                "{} values remaining on the stack at the end of the block",
+                self.operands.len() - frame.height
+            ));
+        }
+        Ok(frame)
+    }
+
+    /// The types expected by a branch to the label at `depth`, `None` if they're unknown
+    fn label_types(&self, depth: u32) -> Result<Option<Vec<ValType>>, String> {
+        let frame = depth
+            .checked_add(1)
+            .and_then(|n| self.frames.len().checked_sub(n as usize))
+            .map(|i| &self.frames[i])
+            .ok_or_else(|| format!("invalid branch depth {depth}"))?;
+        if frame.unknown {
+            return Ok(None);
+        }
+        Ok(Some(match frame.kind {
+            FrameKind::Loop => frame.params.clone(),
+            _ => frame.results.clone(),
+        }))
+    }
+
+    fn call(&mut self, ty: Option<wasm_encoder::FuncType>) -> Result<(), String> {
+        let Some(ty) = ty else {
+            self.set_unreachable();
+            return Ok(());
+        };
+        self.pop_all(ty.params())?;
+        self.push_all(ty.results());
+        Ok(())
+    }
+
+    fn func(&self, index: u32) -> Option<wasm_encoder::FuncType> {
+        self.context.lock().signature(index).cloned()
+    }
+
+    fn func_type(&self, index: u32) -> Option<wasm_encoder::FuncType> {
+        self.context.lock().func_type(index).cloned()
+    }
+
+    fn global(&self, index: u32) -> Option<ValType> {
+        self.context.lock().globals.get(index as usize).copied()
+    }
+
+    pub(crate) fn set_context(&mut self, context: &SharedContext) {
+        self.context = context.clone();
+    }
+
+    /// Update the stack with the effect of `instr`
+    pub(crate) fn apply(&mut self, instr: &Instr) -> Result<(), String> {
+        match instr {
+            Instr::Block(bt) => self.push_frame(FrameKind::Block, bt)?,
+            Instr::Loop(bt) => self.push_frame(FrameKind::Loop, bt)?,
+            Instr::If(bt) => {
+                self.pop(Some(ValType::I32))?;
+                self.push_frame(FrameKind::If, bt)?;
+            }
+            Instr::TryTable(bt, _) => self.push_frame(FrameKind::TryTable, bt)?,
+            Instr::Try(bt) => self.push_frame(FrameKind::Try, bt)?,
+            Instr::Else => {
+                if self.frame().kind != FrameKind::If {
+                    return Err("`else` without a matching `if`".to_string());
+                }
+                let frame = self.pop_frame()?;
+                self.push_all(&frame.params);
+                self.frames.push(Frame {
+                    kind: FrameKind::Else,
+                    unreachable: frame.unknown,
+                    ..frame
+                });
+            }
+            Instr::Catch(_) | Instr::CatchAll => {
+                if self.frame().kind != FrameKind::Try {
+                    return Err("`catch` without a matching `try`".to_string());
+                }
+                // The tag's parameters aren't tracked
+                let frame = self.pop_frame()?;
+                self.frames.push(Frame {
+                    unreachable: true,
+                    ..frame
+                });
+            }
+            Instr::End | Instr::Delegate(_) => {
+                let frame = self.pop_frame()?;
+                if frame.unknown {
+                    self.set_unreachable();
+                } else {
+                    self.push_all(&frame.results);
+                }
+            }
+            Instr::Br(depth) => {
+                if let Some(types) = self.label_types(*depth)? {
+                    self.pop_all(&types)?;
+                }
+                self.set_unreachable();
+            }
+            Instr::BrIf(depth) => {
+                self.pop(Some(ValType::I32))?;
+                match self.label_types(*depth)? {
+                    Some(types) => {
+                        self.pop_all(&types)?;
+                        self.push_all(&types);
+                    }
+                    None => self.set_unreachable(),
+                }
+            }
+            Instr::BrTable(_, default) => {
+                self.pop(Some(ValType::I32))?;
+                if let Some(types) = self.label_types(*default)? {
+                    self.pop_all(&types)?;
+                }
+                self.set_unreachable();
+            }
+            Instr::Return => {
+                let results = self.frames[0].results.clone();
+                self.pop_all(&results)?;
+                self.set_unreachable();
+            }
+            Instr::Unreachable | Instr::Throw(_) | Instr::ThrowRef | Instr::Rethrow(_) => {
+                self.set_unreachable();
+            }
+            Instr::Call(f) => self.call(self.func(*f))?,
+            Instr::ReturnCall(f) => {
+                self.call(self.func(*f))?;
+                self.set_unreachable();
+            }
+            Instr::CallIndirect { type_index, .. } => {
+                self.pop(None)?;
+                self.call(self.func_type(*type_index))?;
+            }
+            Instr::ReturnCallIndirect { type_index, .. } => {
+                self.pop(None)?;
+                self.call(self.func_type(*type_index))?;
+                self.set_unreachable();
+            }
+            Instr::Drop => {
+                self.pop(None)?;
+            }
+            Instr::Select => {
+                self.pop(Some(ValType::I32))?;
+                let a = self.pop(None)?;
+                let b = self.pop(a)?;
+                self.push(b.or(a));
+            }
+            Instr::TypedSelect(ty) => {
+                self.pop(Some(ValType::I32))?;
+                self.pop(Some(*ty))?;
+                self.pop(Some(*ty))?;
+                self.push(Some(*ty));
+            }
+            Instr::LocalGet(index) => self.push(self.local(*index)),
+            Instr::LocalSet(index) => {
+                self.pop(self.local(*index))?;
+            }
+            Instr::LocalTee(index) => {
+                let ty = self.pop(self.local(*index))?;
+                self.push(self.local(*index).or(ty));
+            }
+            Instr::GlobalGet(index) => {
+                self.push(self.global(*index));
+            }
+            Instr::GlobalSet(index) => {
+                self.pop(self.global(*index))?;
+            }
+            instr => match features::with_operator(instr, signature) {
+                Some((params, results)) => {
+                    for ty in params.into_iter().rev() {
+                        self.pop(ty)?;
+                    }
+                    self.operands.extend(results);
+                }
+                None => self.set_unreachable(),
+            },
+        }
+        Ok(())
+    }
+}
@@ -4,6 +4,10 @@ use crate::*;
 pub struct TypeList<T> {
     next: u32,
     pub items: std::collections::BTreeMap<u32, ValType>,
+    pub names: std::collections::BTreeMap<u32, String>,
+    /// Set when the list was built from plain types, no handles have been given out so the
+    /// indices can still be moved
+    relocatable: bool,
     _t: std::marker::PhantomData<T>,
 }
 
@@ -13,6 +17,7 @@ impl<T: From<u32>, U: IntoIterator<Item = ValType>> From<U> for TypeList<T> {
         for x in value.into_iter() {
             dest.push(x);
         }
+        dest.relocatable = true;
         dest
     }
 }
@@ -29,9 +34,36 @@ impl<T: From<u32>> TypeList<T> {
             next: 0,
             _t: Default::default(),
             items: Default::default(),
+            names: Default::default(),
+            relocatable: false,
         }
     }
 
+    pub fn with_offset(offset: u32) -> Self {
+        Self {
+            next: offset,
+            ..Self::new()
+        }
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.items.keys().next().copied().unwrap_or(self.next)
+    }
+
+    /// Move the items to start at `offset`, this is only done for lists that were built from
+    /// plain types
+    pub(crate) fn relocate(&mut self, offset: u32) {
+        if !self.relocatable {
+            return;
+        }
+        self.items = std::mem::take(&mut self.items)
+            .into_values()
+            .enumerate()
+            .map(|(i, ty)| (offset + i as u32, ty))
+            .collect();
+        self.next = offset + self.items.len() as u32;
+    }
+
     pub fn push(&mut self, ty: ValType) -> T {
         let n = self.next;
         self.items.insert(n, ty);
@@ -39,6 +71,11 @@ impl<T: From<u32>> TypeList<T> {
         T::from(n)
     }
 
+    pub fn push_named(&mut self, ty: ValType, name: impl Into<String>) -> T {
+        self.names.insert(self.next, name.into());
+        self.push(ty)
+    }
+
     pub fn ty(&self, local: Local) -> Option<ValType> {
         self.items.get(&local.0).copied()
     }
@@ -58,6 +95,7 @@ impl Local {
 }
 
 impl<'a> Expr<'a> for Local {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::LocalGet(self.0));
     }
@@ -67,6 +105,7 @@ handle!(ParamHandle);
 pub type Param = Index<ParamHandle>;
 
 impl<'a> Expr<'a> for Param {
+    #[track_caller]
     fn expr(self, builder: &mut Builder<'a>) {
         builder.push(Instr::LocalGet(self.0));
     }
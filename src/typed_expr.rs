@@ -0,0 +1,298 @@
+use crate::*;
+
+trait Typed<'a> {
+    fn from_vec(instrs: Vec<Instr<'a>>) -> Self;
+}
+
+macro_rules! typed_expr {
+    ($name:ident, $rust:ty, $const:ident, $get:ident) => {
+        /// An expression that produces a single value of one type, these can be combined using
+        /// operators and are pushed in postfix order
+        #[derive(Debug, Clone)]
+        pub struct $name<'a>(Vec<Instr<'a>>);
+
+        impl<'a> $name<'a> {
+            /// Use the instructions in `instrs` as an expression, they must produce a single
+            /// value of this type
+            pub fn from_instrs(instrs: impl IntoIterator<Item = Instr<'a>>) -> Self {
+                $name(instrs.into_iter().collect())
+            }
+
+            pub fn instrs(&self) -> &[Instr<'a>] {
+                &self.0
+            }
+
+            pub fn set(mut self, local: Local) -> Vec<Instr<'a>> {
+                self.0.push(Instr::LocalSet(local.index()));
+                self.0
+            }
+
+            pub fn tee(mut self, local: Local) -> Self {
+                self.0.push(Instr::LocalTee(local.index()));
+                self
+            }
+
+            pub fn set_global(mut self, global: GlobalIndex) -> Vec<Instr<'a>> {
+                self.0.push(Instr::GlobalSet(global.index()));
+                self.0
+            }
+
+            fn then<T: Typed<'a>>(mut self, instr: Instr<'a>) -> T {
+                self.0.push(instr);
+                T::from_vec(self.0)
+            }
+
+            fn binary<T: Typed<'a>>(mut self, rhs: Self, instr: Instr<'a>) -> T {
+                self.0.extend(rhs.0);
+                self.then(instr)
+            }
+        }
+
+        impl<'a> Typed<'a> for $name<'a> {
+            fn from_vec(instrs: Vec<Instr<'a>>) -> Self {
+                $name(instrs)
+            }
+        }
+
+        impl<'a> From<$rust> for $name<'a> {
+            fn from(value: $rust) -> Self {
+                $name(vec![Instr::$const(value)])
+            }
+        }
+
+        impl<'a> Expr<'a> for $name<'a> {
+            #[track_caller]
+            fn expr(self, builder: &mut Builder<'a>) {
+                builder.extend(self.0);
+            }
+        }
+
+        impl Local {
+            pub fn $get<'a>(self) -> $name<'a> {
+                $name(vec![Instr::LocalGet(self.index())])
+            }
+        }
+
+        impl Param {
+            pub fn $get<'a>(self) -> $name<'a> {
+                $name(vec![Instr::LocalGet(self.index())])
+            }
+        }
+
+        impl GlobalIndex {
+            pub fn $get<'a>(self) -> $name<'a> {
+                $name(vec![Instr::GlobalGet(self.index())])
+            }
+        }
+    };
+}
+
+macro_rules! operator {
+    ($name:ident, $rust:ty, $trait:ident, $method:ident, $instr:ident) => {
+        impl<'a, R: Into<$name<'a>>> std::ops::$trait<R> for $name<'a> {
+            type Output = $name<'a>;
+
+            fn $method(self, rhs: R) -> $name<'a> {
+                self.binary(rhs.into(), Instr::$instr)
+            }
+        }
+
+        impl<'a> std::ops::$trait<$name<'a>> for $rust {
+            type Output = $name<'a>;
+
+            fn $method(self, rhs: $name<'a>) -> $name<'a> {
+                $name::from(self).binary(rhs, Instr::$instr)
+            }
+        }
+    };
+}
+
+macro_rules! methods {
+    ($name:ident { $($method:ident => $instr:ident -> $out:ident),* $(,)? }) => {
+        impl<'a> $name<'a> {
+            $(
+                pub fn $method(self, rhs: impl Into<$name<'a>>) -> $out<'a> {
+                    self.binary(rhs.into(), Instr::$instr)
+                }
+            )*
+        }
+    };
+}
+
+macro_rules! unary_methods {
+    ($name:ident { $($method:ident => $instr:ident -> $out:ident),* $(,)? }) => {
+        impl<'a> $name<'a> {
+            $(
+                pub fn $method(self) -> $out<'a> {
+                    self.then(Instr::$instr)
+                }
+            )*
+        }
+    };
+}
+
+typed_expr!(I32Expr, i32, I32Const, i32);
+typed_expr!(I64Expr, i64, I64Const, i64);
+typed_expr!(F32Expr, f32, F32Const, f32);
+typed_expr!(F64Expr, f64, F64Const, f64);
+
+operator!(I32Expr, i32, Add, add, I32Add);
+operator!(I32Expr, i32, Sub, sub, I32Sub);
+operator!(I32Expr, i32, Mul, mul, I32Mul);
+operator!(I32Expr, i32, Div, div, I32DivS);
+operator!(I32Expr, i32, Rem, rem, I32RemS);
+operator!(I32Expr, i32, BitAnd, bitand, I32And);
+operator!(I32Expr, i32, BitOr, bitor, I32Or);
+operator!(I32Expr, i32, BitXor, bitxor, I32Xor);
+operator!(I32Expr, i32, Shl, shl, I32Shl);
+operator!(I32Expr, i32, Shr, shr, I32ShrS);
+
+operator!(I64Expr, i64, Add, add, I64Add);
+operator!(I64Expr, i64, Sub, sub, I64Sub);
+operator!(I64Expr, i64, Mul, mul, I64Mul);
+operator!(I64Expr, i64, Div, div, I64DivS);
+operator!(I64Expr, i64, Rem, rem, I64RemS);
+operator!(I64Expr, i64, BitAnd, bitand, I64And);
+operator!(I64Expr, i64, BitOr, bitor, I64Or);
+operator!(I64Expr, i64, BitXor, bitxor, I64Xor);
+operator!(I64Expr, i64, Shl, shl, I64Shl);
+operator!(I64Expr, i64, Shr, shr, I64ShrS);
+
+operator!(F32Expr, f32, Add, add, F32Add);
+operator!(F32Expr, f32, Sub, sub, F32Sub);
+operator!(F32Expr, f32, Mul, mul, F32Mul);
+operator!(F32Expr, f32, Div, div, F32Div);
+
+operator!(F64Expr, f64, Add, add, F64Add);
+operator!(F64Expr, f64, Sub, sub, F64Sub);
+operator!(F64Expr, f64, Mul, mul, F64Mul);
+operator!(F64Expr, f64, Div, div, F64Div);
+
+methods!(I32Expr {
+    div_u => I32DivU -> I32Expr,
+    rem_u => I32RemU -> I32Expr,
+    shr_u => I32ShrU -> I32Expr,
+    rotl => I32Rotl -> I32Expr,
+    rotr => I32Rotr -> I32Expr,
+    eq => I32Eq -> I32Expr,
+    ne => I32Ne -> I32Expr,
+    lt_s => I32LtS -> I32Expr,
+    lt_u => I32LtU -> I32Expr,
+    gt_s => I32GtS -> I32Expr,
+    gt_u => I32GtU -> I32Expr,
+    le_s => I32LeS -> I32Expr,
+    le_u => I32LeU -> I32Expr,
+    ge_s => I32GeS -> I32Expr,
+    ge_u => I32GeU -> I32Expr,
+});
+
+methods!(I64Expr {
+    div_u => I64DivU -> I64Expr,
+    rem_u => I64RemU -> I64Expr,
+    shr_u => I64ShrU -> I64Expr,
+    rotl => I64Rotl -> I64Expr,
+    rotr => I64Rotr -> I64Expr,
+    eq => I64Eq -> I32Expr,
+    ne => I64Ne -> I32Expr,
+    lt_s => I64LtS -> I32Expr,
+    lt_u => I64LtU -> I32Expr,
+    gt_s => I64GtS -> I32Expr,
+    gt_u => I64GtU -> I32Expr,
+    le_s => I64LeS -> I32Expr,
+    le_u => I64LeU -> I32Expr,
+    ge_s => I64GeS -> I32Expr,
+    ge_u => I64GeU -> I32Expr,
+});
+
+methods!(F32Expr {
+    min => F32Min -> F32Expr,
+    max => F32Max -> F32Expr,
+    copysign => F32Copysign -> F32Expr,
+    eq => F32Eq -> I32Expr,
+    ne => F32Ne -> I32Expr,
+    lt => F32Lt -> I32Expr,
+    gt => F32Gt -> I32Expr,
+    le => F32Le -> I32Expr,
+    ge => F32Ge -> I32Expr,
+});
+
+methods!(F64Expr {
+    min => F64Min -> F64Expr,
+    max => F64Max -> F64Expr,
+    copysign => F64Copysign -> F64Expr,
+    eq => F64Eq -> I32Expr,
+    ne => F64Ne -> I32Expr,
+    lt => F64Lt -> I32Expr,
+    gt => F64Gt -> I32Expr,
+    le => F64Le -> I32Expr,
+    ge => F64Ge -> I32Expr,
+});
+
+unary_methods!(I32Expr {
+    eqz => I32Eqz -> I32Expr,
+    clz => I32Clz -> I32Expr,
+    ctz => I32Ctz -> I32Expr,
+    popcnt => I32Popcnt -> I32Expr,
+    extend_s => I64ExtendI32S -> I64Expr,
+    extend_u => I64ExtendI32U -> I64Expr,
+    convert_f32_s => F32ConvertI32S -> F32Expr,
+    convert_f32_u => F32ConvertI32U -> F32Expr,
+    convert_f64_s => F64ConvertI32S -> F64Expr,
+    convert_f64_u => F64ConvertI32U -> F64Expr,
+});
+
+unary_methods!(I64Expr {
+    eqz => I64Eqz -> I32Expr,
+    clz => I64Clz -> I64Expr,
+    ctz => I64Ctz -> I64Expr,
+    popcnt => I64Popcnt -> I64Expr,
+    wrap => I32WrapI64 -> I32Expr,
+    convert_f32_s => F32ConvertI64S -> F32Expr,
+    convert_f32_u => F32ConvertI64U -> F32Expr,
+    convert_f64_s => F64ConvertI64S -> F64Expr,
+    convert_f64_u => F64ConvertI64U -> F64Expr,
+});
+
+unary_methods!(F32Expr {
+    abs => F32Abs -> F32Expr,
+    ceil => F32Ceil -> F32Expr,
+    floor => F32Floor -> F32Expr,
+    trunc => F32Trunc -> F32Expr,
+    nearest => F32Nearest -> F32Expr,
+    sqrt => F32Sqrt -> F32Expr,
+    promote => F64PromoteF32 -> F64Expr,
+    trunc_i32_s => I32TruncF32S -> I32Expr,
+    trunc_i32_u => I32TruncF32U -> I32Expr,
+    trunc_i64_s => I64TruncF32S -> I64Expr,
+    trunc_i64_u => I64TruncF32U -> I64Expr,
+});
+
+unary_methods!(F64Expr {
+    abs => F64Abs -> F64Expr,
+    ceil => F64Ceil -> F64Expr,
+    floor => F64Floor -> F64Expr,
+    trunc => F64Trunc -> F64Expr,
+    nearest => F64Nearest -> F64Expr,
+    sqrt => F64Sqrt -> F64Expr,
+    demote => F32DemoteF64 -> F32Expr,
+    trunc_i32_s => I32TruncF64S -> I32Expr,
+    trunc_i32_u => I32TruncF64U -> I32Expr,
+    trunc_i64_s => I64TruncF64S -> I64Expr,
+    trunc_i64_u => I64TruncF64U -> I64Expr,
+});
+
+impl<'a> std::ops::Neg for F32Expr<'a> {
+    type Output = F32Expr<'a>;
+
+    fn neg(self) -> F32Expr<'a> {
+        self.then(Instr::F32Neg)
+    }
+}
+
+impl<'a> std::ops::Neg for F64Expr<'a> {
+    type Output = F64Expr<'a>;
+
+    fn neg(self) -> F64Expr<'a> {
+        self.then(Instr::F64Neg)
+    }
+}
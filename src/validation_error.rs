@@ -0,0 +1,141 @@
+use crate::*;
+
+/// A validation error mapped back to the function and instruction that caused it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub message: String,
+    pub offset: usize,
+    pub function: Option<String>,
+    pub function_index: Option<FunctionIndex>,
+    /// Index of the offending instruction in `Function::body.instrs`, this is equal to the number
+    /// of instructions when the error is caused by the implicit `end` of the function
+    pub instr: Option<usize>,
+    pub expected: Option<String>,
+    pub actual: Option<String>,
+    pub location: Option<&'static std::panic::Location<'static>>,
+    /// The instructions surrounding the offending instruction
+    pub context: Vec<String>,
+}
+
+const CONTEXT: usize = 3;
+
+impl ValidationError {
+    pub(crate) fn new(
+        err: &wasmparser::BinaryReaderError,
+        wasm: &[u8],
+        defs: &[Function],
+    ) -> ValidationError {
+        let mut error = ValidationError::from_error(err);
+        if let Some((func, instr)) = locate(wasm, err.offset()) {
+            if let Some(def) = defs.get(func) {
+                error.set_function(def, instr);
+            }
+        }
+        error
+    }
+
+    /// An error from validating the body of `def` on its own
+    pub(crate) fn in_function(
+        err: &wasmparser::BinaryReaderError,
+        body: &wasmparser::FunctionBody,
+        def: &Function,
+    ) -> ValidationError {
+        let mut error = ValidationError::from_error(err);
+        if let Some(instr) = locate_instr(body, err.offset()) {
+            error.set_function(def, instr);
+        }
+        error
+    }
+
+    pub(crate) fn from_error(err: &wasmparser::BinaryReaderError) -> ValidationError {
+        let message = err.message().to_string();
+        let (expected, actual) = match message
+            .split_once("expected ")
+            .and_then(|(_, rest)| rest.split_once(", found "))
+        {
+            Some((expected, actual)) => (Some(expected.to_string()), Some(actual.to_string())),
+            None => (None, None),
+        };
+        ValidationError {
+            message,
+            offset: err.offset(),
+            function: None,
+            function_index: None,
+            instr: None,
+            expected,
+            actual,
+            location: None,
+            context: vec![],
+        }
+    }
+
+    fn set_function(&mut self, def: &Function, instr: usize) {
+        let instrs = &def.body.instrs;
+        self.function = Some(def.name.clone());
+        self.function_index = Some(def.index());
+        self.instr = Some(instr);
+        self.location = def.body.location(instr.min(instrs.len().saturating_sub(1)));
+        let start = instr.saturating_sub(CONTEXT);
+        let end = (instr + CONTEXT + 1).min(instrs.len() + 1);
+        self.context = (start..end)
+            .map(|i| {
+                let marker = if i == instr { ">" } else { " " };
+                match instrs.get(i) {
+                    Some(x) => format!("{marker} {i}: {x:?}"),
+                    None => format!("{marker} {i}: End"),
+                }
+            })
+            .collect();
+    }
+}
+
+/// Find the defined function and operator that contain `offset`
+fn locate(wasm: &[u8], offset: usize) -> Option<(usize, usize)> {
+    let mut func = 0;
+    for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+        let wasmparser::Payload::CodeSectionEntry(body) = payload.ok()? else {
+            continue;
+        };
+        if !body.range().contains(&offset) {
+            func += 1;
+            continue;
+        }
+        return Some((func, locate_instr(&body, offset)?));
+    }
+    None
+}
+
+/// Find the operator in `body` that contains `offset`
+fn locate_instr(body: &wasmparser::FunctionBody, offset: usize) -> Option<usize> {
+    let mut reader = body.get_operators_reader().ok()?;
+    let mut index = None;
+    while !reader.eof() {
+        let (_, op_offset) = reader.read_with_offset().ok()?;
+        if op_offset > offset {
+            break;
+        }
+        index = Some(index.map_or(0, |i| i + 1));
+    }
+    Some(index.unwrap_or(0))
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at offset 0x{:x})", self.message, self.offset)?;
+        if let Some(name) = &self.function {
+            write!(f, " in function `{name}`")?;
+        }
+        if let Some(instr) = self.instr {
+            write!(f, " at instruction {instr}")?;
+        }
+        if let Some(loc) = self.location {
+            write!(f, " emitted from {loc}")?;
+        }
+        for line in &self.context {
+            write!(f, "\n  {line}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationError {}